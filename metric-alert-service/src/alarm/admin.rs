@@ -0,0 +1,211 @@
+use crate::alarm::condition::Severity;
+use crate::alarm::schedule::{EvaluationStats, LagStats};
+use crate::alarm::silence::{Silence, SilenceSummary};
+use crate::alarm::state::AlarmState;
+use crate::alarm::{AlarmConfig, AlarmSet};
+use crate::metric::{read_string, write_string};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+
+/// Manages the alarms of a running server. Changes are saved to the alarm file
+#[derive(Debug, Deserialize, Serialize)]
+pub enum AlarmAction {
+    List,
+    /// Adds an alarm, which must have a name no other alarm has
+    Add(AlarmConfig),
+    /// Replaces the alarm with the same name
+    Update(AlarmConfig),
+    Delete(String),
+    /// Muted alarms are still evaluated, but their transitions aren't notified
+    Silence { name: String, muted: bool },
+    /// Lag of the evaluations of every alarm
+    Stats,
+    ListSilences,
+    /// Adds a silence, which must have a name no other silence has
+    AddSilence(Silence),
+    DeleteSilence(String),
+}
+
+/// Answer to an `AlarmAction`, listings are sent as a JSON array and stats as an object
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum AlarmReply {
+    Done,
+    Alarms(Vec<AlarmSummary>),
+    Silences(Vec<SilenceSummary>),
+    Stats(LagStats),
+}
+
+/// An alarm and the state of its last evaluation, as listed to clients
+#[derive(Debug, Serialize)]
+pub struct AlarmSummary {
+    #[serde(flatten)]
+    pub config: AlarmConfig,
+    pub state: AlarmState,
+    pub severity: Option<Severity>,
+    pub since: DateTime<Utc>,
+    pub last_evaluation: Option<EvaluationStats>,
+}
+
+/// Handle to the alarms evaluated by an `AlarmManager`
+#[derive(Clone)]
+pub struct AlarmAdmin {
+    pub(super) alarms: Arc<Mutex<AlarmSet>>,
+    /// Sent by the clients allowed to manage the alarms, none are without it
+    pub(super) token: Option<String>,
+}
+
+impl AlarmAction {
+    pub fn from_stream<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let mut code = [0];
+        stream.read_exact(&mut code)?;
+        match code[0] {
+            b'L' => Ok(AlarmAction::List),
+            b'A' => Ok(AlarmAction::Add(read_config(stream)?)),
+            b'U' => Ok(AlarmAction::Update(read_config(stream)?)),
+            b'D' => Ok(AlarmAction::Delete(read_string(stream)?)),
+            b'S' => {
+                let name = read_string(stream)?;
+                let mut muted = [0];
+                stream.read_exact(&mut muted)?;
+                Ok(AlarmAction::Silence {
+                    name,
+                    muted: muted[0] == b'Y',
+                })
+            }
+            b'T' => Ok(AlarmAction::Stats),
+            // Silences use the lowercase codes
+            b'l' => Ok(AlarmAction::ListSilences),
+            b'a' => Ok(AlarmAction::AddSilence(serde_json::from_str(&read_string(stream)?)?)),
+            b'd' => Ok(AlarmAction::DeleteSilence(read_string(stream)?)),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid alarm action")),
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        match self {
+            AlarmAction::List => stream.write_all(b"L"),
+            AlarmAction::Add(config) => {
+                stream.write_all(b"A")?;
+                write_string(stream, &serde_json::to_string(config)?)
+            }
+            AlarmAction::Update(config) => {
+                stream.write_all(b"U")?;
+                write_string(stream, &serde_json::to_string(config)?)
+            }
+            AlarmAction::Delete(name) => {
+                stream.write_all(b"D")?;
+                write_string(stream, name)
+            }
+            AlarmAction::Silence { name, muted } => {
+                stream.write_all(b"S")?;
+                write_string(stream, name)?;
+                stream.write_all(if *muted { b"Y" } else { b"N" })
+            }
+            AlarmAction::Stats => stream.write_all(b"T"),
+            AlarmAction::ListSilences => stream.write_all(b"l"),
+            AlarmAction::AddSilence(silence) => {
+                stream.write_all(b"a")?;
+                write_string(stream, &serde_json::to_string(silence)?)
+            }
+            AlarmAction::DeleteSilence(name) => {
+                stream.write_all(b"d")?;
+                write_string(stream, name)
+            }
+        }
+    }
+}
+
+impl AlarmAdmin {
+    pub fn handle(&self, token: &str, action: AlarmAction) -> io::Result<AlarmReply> {
+        let Some(expected) = &self.token else {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Alarms can't be managed without an admin token"));
+        };
+        if !same_token(expected, token) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Invalid admin token"));
+        }
+        let mut alarms = self.alarms.lock().unwrap();
+        match action {
+            AlarmAction::List => return Ok(AlarmReply::Alarms(alarms.list())),
+            AlarmAction::Add(config) => alarms.add(config)?,
+            AlarmAction::Update(config) => alarms.update(config)?,
+            AlarmAction::Delete(name) => alarms.delete(&name)?,
+            AlarmAction::Silence { name, muted } => alarms.silence(&name, muted)?,
+            AlarmAction::Stats => return Ok(AlarmReply::Stats(alarms.lag_stats())),
+            AlarmAction::ListSilences => return Ok(AlarmReply::Silences(alarms.silences.list())),
+            AlarmAction::AddSilence(silence) => alarms.silences.add(silence)?,
+            AlarmAction::DeleteSilence(name) => alarms.silences.delete(&name)?,
+        }
+        Ok(AlarmReply::Done)
+    }
+}
+
+/// Compares every byte, so the time taken doesn't tell how much of a guess was right
+fn same_token(expected: &str, token: &str) -> bool {
+    expected.len() == token.len()
+        && expected.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Alarm configurations are sent as JSON
+fn read_config<R: Read>(stream: &mut R) -> io::Result<AlarmConfig> {
+    Ok(serde_json::from_str(&read_string(stream)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alarm::tests::load_set;
+    use crate::metric::MetricAction;
+    use crate::test_util::temp_path;
+
+    fn admin(name: &str, token: Option<&str>) -> AlarmAdmin {
+        let alarm_file = temp_path(&format!("admin-{}.jsonl", name));
+        let set = load_set(&alarm_file, &[]);
+        std::fs::remove_file(&alarm_file).unwrap();
+        AlarmAdmin {
+            alarms: Arc::new(Mutex::new(set.unwrap())),
+            token: token.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn actions_need_the_admin_token() {
+        let admin = admin("token", Some("secret"));
+        assert!(matches!(admin.handle("secret", AlarmAction::List), Ok(AlarmReply::Alarms(alarms)) if alarms.is_empty()));
+        for token in ["", "secre", "secret!", "Secret"] {
+            let Err(e) = admin.handle(token, AlarmAction::List) else {
+                panic!("{:?} was accepted", token);
+            };
+            assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        }
+    }
+
+    #[test]
+    fn alarms_cant_be_managed_without_a_token() {
+        let Err(e) = admin("no-token", None).handle("", AlarmAction::ListSilences) else {
+            panic!("the silences were listed without a token");
+        };
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn wire_actions_carry_the_token() {
+        let action = MetricAction::Alarm {
+            token: "secret".to_string(),
+            action: AlarmAction::Silence {
+                name: "cpu_high".to_string(),
+                muted: true,
+            },
+        };
+        let mut buf = vec![];
+        action.write_to(&mut buf).unwrap();
+        let MetricAction::Alarm { token, action } = MetricAction::from_stream(buf.as_slice()).unwrap() else {
+            panic!("not an alarm action");
+        };
+        assert_eq!(token, "secret");
+        assert!(matches!(action, AlarmAction::Silence { name, muted: true } if name == "cpu_high"));
+    }
+}
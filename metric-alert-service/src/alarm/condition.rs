@@ -0,0 +1,290 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Operator comparing the aggregated value of an alarm with its `limit` or `range`
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Operator {
+    #[default]
+    #[serde(rename = ">")]
+    Greater,
+    #[serde(rename = ">=")]
+    GreaterOrEqual,
+    #[serde(rename = "<")]
+    Less,
+    #[serde(rename = "<=")]
+    LessOrEqual,
+    #[serde(rename = "==")]
+    Equal,
+    #[serde(rename = "!=")]
+    NotEqual,
+    /// Inside the range, bounds included
+    #[serde(rename = "inside")]
+    Inside,
+    /// Outside the range, bounds excluded
+    #[serde(rename = "outside")]
+    Outside,
+}
+
+/// What an alarm does when its query finds no samples, unless it counts them
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum NoDataPolicy {
+    /// The condition doesn't hold, resolving the alarm
+    #[default]
+    #[serde(rename = "ok")]
+    Ok,
+    /// The alarm keeps its state until there is data again
+    #[serde(rename = "keep")]
+    Keep,
+    /// The alarm triggers as critical
+    #[serde(rename = "alert")]
+    Alert,
+}
+
+/// Condition on the aggregated value of an alarm that makes it fire
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Condition {
+    Compare(Operator, f32),
+    Inside(f32, f32),
+    Outside(f32, f32),
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Critical,
+}
+
+/// Conditions of each severity of an alarm. Once active, an alarm only clears when the
+/// recovery condition stops holding, so values hovering around a threshold don't make it flap
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Thresholds {
+    pub critical: Condition,
+    pub warning: Option<Condition>,
+    pub recovery: Option<Condition>,
+}
+
+impl Condition {
+    /// Builds the condition of an alarm. Comparisons need a `limit` and range operators a
+    /// `range`
+    pub fn new(operator: Operator, limit: Option<f32>, range: Option<(f32, f32)>) -> Result<Self, String> {
+        match (operator, limit, range) {
+            (Operator::Inside | Operator::Outside, _, None) => {
+                Err(format!("the {} operator needs a range", operator))
+            }
+            (Operator::Inside | Operator::Outside, _, Some((from, to))) if from > to => {
+                Err(format!("the range [{}, {}] is empty", from, to))
+            }
+            (Operator::Inside, _, Some((from, to))) => Ok(Condition::Inside(from, to)),
+            (Operator::Outside, _, Some((from, to))) => Ok(Condition::Outside(from, to)),
+            (_, None, _) => Err(format!("the {} operator needs a limit", operator)),
+            (_, Some(limit), _) => Ok(Condition::Compare(operator, limit)),
+        }
+    }
+
+    /// Whether this condition holds for every value `other` holds for
+    fn covers(&self, other: &Condition) -> bool {
+        match (*self, *other) {
+            (Condition::Compare(Operator::Greater | Operator::GreaterOrEqual, a), Condition::Compare(_, b)) => {
+                a <= b
+            }
+            (Condition::Compare(Operator::Less | Operator::LessOrEqual, a), Condition::Compare(_, b)) => {
+                a >= b
+            }
+            (Condition::Compare(_, a), Condition::Compare(_, b)) => a == b,
+            (Condition::Inside(a_from, a_to), Condition::Inside(b_from, b_to)) => {
+                a_from <= b_from && a_to >= b_to
+            }
+            (Condition::Outside(a_from, a_to), Condition::Outside(b_from, b_to)) => {
+                a_from >= b_from && a_to <= b_to
+            }
+            _ => false,
+        }
+    }
+
+    pub fn holds(&self, value: f32) -> bool {
+        match *self {
+            Condition::Compare(Operator::Greater, limit) => value > limit,
+            Condition::Compare(Operator::GreaterOrEqual, limit) => value >= limit,
+            Condition::Compare(Operator::Less, limit) => value < limit,
+            Condition::Compare(Operator::LessOrEqual, limit) => value <= limit,
+            Condition::Compare(Operator::Equal, limit) => value == limit,
+            Condition::Compare(Operator::NotEqual, limit) => value != limit,
+            Condition::Compare(Operator::Inside | Operator::Outside, _) => false,
+            Condition::Inside(from, to) => from <= value && value <= to,
+            Condition::Outside(from, to) => value < from || value > to,
+        }
+    }
+}
+
+impl Thresholds {
+    /// The warning condition must trigger before the critical one, and the recovery condition
+    /// must hold whenever any of them does
+    pub fn new(
+        critical: Condition,
+        warning: Option<Condition>,
+        recovery: Option<Condition>,
+    ) -> Result<Self, String> {
+        if warning.is_some_and(|warning| !warning.covers(&critical)) {
+            return Err("the warning threshold must trigger before the critical one".to_string());
+        }
+        let trigger = warning.unwrap_or(critical);
+        if recovery.is_some_and(|recovery| !recovery.covers(&trigger)) {
+            return Err("the recovery threshold must hold while the alarm triggers".to_string());
+        }
+        Ok(Self {
+            critical,
+            warning,
+            recovery,
+        })
+    }
+
+    /// Severity of an alarm with a value, `current` being its severity if it is active
+    pub fn severity(&self, value: f32, current: Option<Severity>) -> Option<Severity> {
+        if self.critical.holds(value) {
+            Some(Severity::Critical)
+        } else if self.warning.is_some_and(|warning| warning.holds(value)) {
+            Some(Severity::Warning)
+        } else if self.recovery.is_some_and(|recovery| recovery.holds(value)) {
+            current
+        } else {
+            None
+        }
+    }
+
+    /// Condition that triggers a severity
+    pub fn condition(&self, severity: Severity) -> Condition {
+        match severity {
+            Severity::Critical => self.critical,
+            Severity::Warning => self.warning.unwrap_or(self.critical),
+        }
+    }
+
+    /// Condition that keeps an alarm with a severity active
+    pub fn active_condition(&self, severity: Severity) -> Condition {
+        self.recovery.unwrap_or_else(|| self.condition(severity))
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => f.write_str("WARNING"),
+            Severity::Critical => f.write_str("CRITICAL"),
+        }
+    }
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            Operator::Greater => ">",
+            Operator::GreaterOrEqual => ">=",
+            Operator::Less => "<",
+            Operator::LessOrEqual => "<=",
+            Operator::Equal => "==",
+            Operator::NotEqual => "!=",
+            Operator::Inside => "inside",
+            Operator::Outside => "outside",
+        };
+        f.write_str(symbol)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::Compare(operator, limit) => write!(f, "{} {}", operator, limit),
+            Condition::Inside(from, to) => write!(f, "inside [{}, {}]", from, to),
+            Condition::Outside(from, to) => write!(f, "outside [{}, {}]", from, to),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compare(operator: Operator, limit: f32) -> Condition {
+        Condition::new(operator, Some(limit), None).unwrap()
+    }
+
+    #[test]
+    fn operators_compare_with_their_limit_or_range() {
+        assert!(compare(Operator::Greater, 1.0).holds(1.5));
+        assert!(!compare(Operator::Greater, 1.0).holds(1.0));
+        assert!(compare(Operator::GreaterOrEqual, 1.0).holds(1.0));
+        assert!(compare(Operator::Less, 1.0).holds(0.5));
+        assert!(compare(Operator::LessOrEqual, 1.0).holds(1.0));
+        assert!(compare(Operator::Equal, 1.0).holds(1.0));
+        assert!(compare(Operator::NotEqual, 1.0).holds(2.0));
+        let inside = Condition::new(Operator::Inside, None, Some((1.0, 2.0))).unwrap();
+        let outside = Condition::new(Operator::Outside, None, Some((1.0, 2.0))).unwrap();
+        assert!(inside.holds(1.0) && inside.holds(2.0) && !inside.holds(2.5));
+        assert!(!outside.holds(1.0) && !outside.holds(2.0) && outside.holds(0.5));
+        assert!(!compare(Operator::Greater, 1.0).holds(f32::NAN));
+    }
+
+    #[test]
+    fn conditions_need_their_limit_or_range() {
+        assert!(Condition::new(Operator::Greater, None, Some((1.0, 2.0))).is_err());
+        assert!(Condition::new(Operator::Inside, Some(1.0), None).is_err());
+        assert!(Condition::new(Operator::Outside, None, Some((2.0, 1.0))).is_err());
+    }
+
+    #[test]
+    fn warnings_must_trigger_before_critical_and_recovery_must_cover_them() {
+        let critical = compare(Operator::Greater, 90.0);
+        assert!(Thresholds::new(critical, Some(compare(Operator::Greater, 80.0)), None).is_ok());
+        assert!(Thresholds::new(critical, Some(compare(Operator::Greater, 95.0)), None).is_err());
+        assert!(Thresholds::new(critical, Some(compare(Operator::Less, 80.0)), None).is_err());
+        let warning = Some(compare(Operator::Greater, 80.0));
+        assert!(Thresholds::new(critical, warning, Some(compare(Operator::Greater, 70.0))).is_ok());
+        assert!(Thresholds::new(critical, warning, Some(compare(Operator::Greater, 85.0))).is_err());
+        let low = compare(Operator::Less, 10.0);
+        assert!(Thresholds::new(low, Some(compare(Operator::Less, 20.0)), Some(compare(Operator::Less, 25.0))).is_ok());
+        let outside = Condition::new(Operator::Outside, None, Some((10.0, 20.0))).unwrap();
+        let recovery = Condition::new(Operator::Outside, None, Some((12.0, 18.0))).unwrap();
+        assert!(Thresholds::new(outside, None, Some(recovery)).is_ok());
+        assert!(Thresholds::new(recovery, None, Some(outside)).is_err());
+    }
+
+    #[test]
+    fn recovery_keeps_the_current_severity() {
+        let thresholds = Thresholds::new(
+            compare(Operator::Greater, 90.0),
+            Some(compare(Operator::Greater, 80.0)),
+            Some(compare(Operator::Greater, 70.0)),
+        )
+        .unwrap();
+        assert_eq!(thresholds.severity(95.0, None), Some(Severity::Critical));
+        assert_eq!(thresholds.severity(85.0, None), Some(Severity::Warning));
+        // Between the recovery and the warning thresholds the alarm keeps what it had
+        assert_eq!(thresholds.severity(75.0, None), None);
+        assert_eq!(thresholds.severity(75.0, Some(Severity::Critical)), Some(Severity::Critical));
+        assert_eq!(thresholds.severity(75.0, Some(Severity::Warning)), Some(Severity::Warning));
+        assert_eq!(thresholds.severity(65.0, Some(Severity::Critical)), None);
+        // A critical alarm back above the warning threshold becomes a warning
+        assert_eq!(thresholds.severity(85.0, Some(Severity::Critical)), Some(Severity::Warning));
+    }
+
+    #[test]
+    fn hovering_values_dont_flap() {
+        let thresholds = Thresholds::new(
+            compare(Operator::Greater, 90.0),
+            None,
+            Some(compare(Operator::Greater, 80.0)),
+        )
+        .unwrap();
+        let mut severity = None;
+        let mut changes = 0;
+        for value in [91.0, 89.0, 90.5, 85.0, 89.9, 91.0, 79.0] {
+            let next = thresholds.severity(value, severity);
+            changes += usize::from(next != severity);
+            severity = next;
+        }
+        assert_eq!(changes, 2);
+        assert_eq!(severity, None);
+        assert_eq!(thresholds.active_condition(Severity::Critical), compare(Operator::Greater, 80.0));
+        assert_eq!(thresholds.condition(Severity::Warning), compare(Operator::Greater, 90.0));
+    }
+}
@@ -0,0 +1,230 @@
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Timelike, Utc};
+use std::fmt;
+
+/// Times matching a five-field cron expression in UTC. As in cron, `a/n` steps up to the
+/// highest value, and a time matching either day field matches when both are restricted
+#[derive(Clone, Debug, PartialEq)]
+pub struct CronSchedule {
+    expression: String,
+    /// Bit sets of the allowed values of each field
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    /// Sunday is 0
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl CronSchedule {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let fields = expression.split_whitespace().collect::<Vec<_>>();
+        let [minutes, hours, days, months, weekdays] = fields.as_slice() else {
+            return Err(format!("{:?} doesn't have the 5 fields of a cron expression", expression));
+        };
+        // Sunday is both 0 and 7
+        let mut weekday_set = parse_field(weekdays, 0, 7)?;
+        if weekday_set & (1 << 7) != 0 {
+            weekday_set |= 1;
+        }
+        Ok(Self {
+            expression: expression.to_string(),
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekday_set,
+            any_day: *days == "*",
+            any_weekday: *weekdays == "*",
+        })
+    }
+
+    /// Whether the minute of `time` matches
+    pub fn matches(&self, time: DateTime<Utc>) -> bool {
+        allows(self.minutes, time.minute())
+            && allows(self.hours, time.hour())
+            && self.matches_day(time.naive_utc().date())
+    }
+
+    /// Latest matching minute between `since` and `until`, both included. Goes back a day at a
+    /// time, so it takes as many steps as days between them
+    pub fn latest(&self, until: DateTime<Utc>, since: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut day = until.naive_utc().date();
+        // Latest hour and minute of the day that may match
+        let mut bound = (until.hour(), until.minute());
+        while day >= since.naive_utc().date() {
+            if self.matches_day(day) {
+                let (last_hour, last_minute) = bound;
+                let latest = (0..=last_hour).rev().filter(|hour| allows(self.hours, *hour)).find_map(|hour| {
+                    let minute = highest(self.minutes, if hour == last_hour { last_minute } else { 59 })?;
+                    Some(Utc.from_utc_datetime(&day.and_hms_opt(hour, minute, 0)?))
+                });
+                if let Some(latest) = latest {
+                    return Some(latest).filter(|latest| *latest >= since);
+                }
+            }
+            day = day.pred_opt()?;
+            bound = (23, 59);
+        }
+        None
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = allows(self.days, date.day());
+        let weekday = allows(self.weekdays, date.weekday().num_days_from_sunday());
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        allows(self.months, date.month()) && day_matches
+    }
+}
+
+fn allows(set: u64, value: u32) -> bool {
+    set & (1 << value) != 0
+}
+
+/// Highest value of the set up to `max`, which is at most 63
+fn highest(set: u64, max: u32) -> Option<u32> {
+    let allowed = set & (u64::MAX >> (63 - max));
+    (allowed != 0).then(|| 63 - allowed.leading_zeros())
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(parse_value(step, 1, max)?)),
+            None => (part, None),
+        };
+        let (from, to) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((from, to)) => (parse_value(from, min, max)?, parse_value(to, min, max)?),
+            None if step.is_some() => (parse_value(range, min, max)?, max),
+            None => {
+                let value = parse_value(range, min, max)?;
+                (value, value)
+            }
+        };
+        if from > to {
+            return Err(format!("the range {} is empty", range));
+        }
+        for value in (from..=to).step_by(step.unwrap_or(1) as usize) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+fn parse_value(value: &str, min: u32, max: u32) -> Result<u32, String> {
+    match value.parse() {
+        Ok(value) if (min..=max).contains(&value) => Ok(value),
+        _ => Err(format!("{:?} isn't a value between {} and {}", value, min, max)),
+    }
+}
+
+impl fmt::Display for CronSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn cron(expression: &str) -> CronSchedule {
+        CronSchedule::parse(expression).unwrap()
+    }
+
+    fn values(set: u64) -> Vec<u32> {
+        (0..64).filter(|value| allows(set, *value)).collect()
+    }
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.ymd(year, month, day).and_hms(hour, minute, 0)
+    }
+
+    #[test]
+    fn fields_take_values_ranges_and_steps() {
+        assert_eq!(values(parse_field("*", 1, 12).unwrap()), (1..=12).collect::<Vec<_>>());
+        assert_eq!(values(parse_field("1,3-5", 0, 59).unwrap()), [1, 3, 4, 5]);
+        assert_eq!(values(parse_field("*/15", 0, 59).unwrap()), [0, 15, 30, 45]);
+        assert_eq!(values(parse_field("10-20/5", 0, 59).unwrap()), [10, 15, 20]);
+        assert_eq!(values(parse_field("50/3", 0, 59).unwrap()), [50, 53, 56, 59]);
+        // A single value with a step still goes up to the highest value
+        assert_eq!(values(parse_field("20/1", 0, 23).unwrap()), [20, 21, 22, 23]);
+        assert_eq!(values(parse_field("5", 0, 23).unwrap()), [5]);
+    }
+
+    #[test]
+    fn invalid_fields_are_rejected() {
+        for field in ["60", "5-1", "*/0", "a", "", "1-", "-1", "1,,2"] {
+            assert!(parse_field(field, 0, 59).is_err(), "{:?} was accepted", field);
+        }
+        assert!(CronSchedule::parse("* * * *").is_err());
+        assert!(CronSchedule::parse("* * 0 * *").is_err());
+    }
+
+    #[test]
+    fn matches_every_field() {
+        // 2022-04-15 is a Friday
+        let schedule = cron("30 2 * 4 *");
+        assert!(schedule.matches(at(2022, 4, 15, 2, 30) + Duration::seconds(59)));
+        assert!(!schedule.matches(at(2022, 4, 15, 2, 31)));
+        assert!(!schedule.matches(at(2022, 4, 15, 3, 30)));
+        assert!(!schedule.matches(at(2022, 5, 15, 2, 30)));
+    }
+
+    #[test]
+    fn restricted_days_match_either_day() {
+        let schedule = cron("0 0 1 * 1");
+        assert!(schedule.matches(at(2022, 4, 1, 0, 0)));
+        assert!(schedule.matches(at(2022, 4, 18, 0, 0)));
+        assert!(!schedule.matches(at(2022, 4, 15, 0, 0)));
+        // Otherwise both must match
+        let mondays = cron("0 0 * * 1");
+        assert!(!mondays.matches(at(2022, 4, 1, 0, 0)));
+        assert!(mondays.matches(at(2022, 4, 18, 0, 0)));
+    }
+
+    #[test]
+    fn sunday_is_both_0_and_7() {
+        let sunday = at(2022, 4, 17, 0, 0);
+        assert!(cron("0 0 * * 0").matches(sunday));
+        assert!(cron("0 0 * * 7").matches(sunday));
+        assert!(cron("0 0 * * 5-7").matches(sunday));
+        assert!(!cron("0 0 * * 1-6").matches(sunday));
+    }
+
+    #[test]
+    fn latest_finds_the_last_matching_minute() {
+        let schedule = cron("15 */6 * * 1-5");
+        let now = at(2022, 4, 18, 12, 20) + Duration::seconds(30);
+        assert_eq!(schedule.latest(now, now - Duration::days(7)), Some(at(2022, 4, 18, 12, 15)));
+        assert_eq!(schedule.latest(at(2022, 4, 18, 12, 14), now - Duration::days(7)), Some(at(2022, 4, 18, 6, 15)));
+        // Over the weekend, back to Friday
+        assert_eq!(schedule.latest(at(2022, 4, 18, 0, 10), now - Duration::days(7)), Some(at(2022, 4, 15, 18, 15)));
+        assert_eq!(schedule.latest(at(2022, 4, 18, 0, 10), at(2022, 4, 16, 0, 0)), None);
+        assert_eq!(cron("0 0 31 2 *").latest(now, now - Duration::days(7)), None);
+    }
+
+    #[test]
+    fn latest_agrees_with_matches() {
+        let since = at(2022, 2, 26, 22, 0);
+        for expression in ["*/7 1,23 * * *", "0 0 1 3 *", "59 23 28 2 *", "5-10/2 * * * 0", "0 12 1 * 3"] {
+            let schedule = cron(expression);
+            let mut expected = None;
+            let mut minute = since;
+            while minute <= at(2022, 3, 6, 0, 0) {
+                if schedule.matches(minute) {
+                    expected = Some(minute);
+                }
+                assert_eq!(schedule.latest(minute, since), expected, "{} at {}", expression, minute);
+                minute = minute + Duration::minutes(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,892 @@
+pub mod admin;
+pub mod condition;
+pub mod cron;
+pub mod notifier;
+pub mod schedule;
+pub mod silence;
+pub mod state;
+
+use crate::alarm::admin::{AlarmAdmin, AlarmSummary};
+use crate::alarm::condition::{Condition, NoDataPolicy, Operator, Severity, Thresholds};
+use crate::alarm::notifier::{AlarmEvent, NotifierConfig, NotifierQueue};
+use crate::alarm::schedule::{EvaluationStats, LagStats, Schedule};
+use crate::alarm::silence::SilenceSet;
+use crate::alarm::state::{AlarmState, AlarmStatus};
+use crate::metric::query::{MetricSelector, QueryAggregation, QueryParams, QueryResult};
+use crate::metric::query_handler::run_query;
+use crate::metric::router::ShardRouter;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+use std::sync::atomic::{AtomicBool, Ordering};
+use crossbeam_channel::{unbounded as channel, RecvTimeoutError, Sender};
+use chrono::{Duration, Utc};
+use log::{debug, error, info, warn};
+use threadpool::ThreadPool;
+use crate::metric::time::TimeRange;
+use crate::metric::Query;
+
+/// Longest wait of the scheduler, so reloads and shutdowns aren't delayed
+const SCHEDULE_TICK: std::time::Duration = std::time::Duration::from_secs(1);
+/// Alarms evaluated at the same time, each one waiting for its query
+const EVALUATION_POOL_SIZE: usize = 8;
+/// Longest interval and timeout of an alarm
+const MAX_INTERVAL_SECS: f32 = 24.0 * 3600.0;
+/// Most data an evaluation may query, as windows, lookbacks and absences
+const MAX_LOOKBACK_SECS: f32 = 7.0 * 24.0 * 3600.0;
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct AlarmConfig {
+    /// Identifies the alarm when managed over the wire, unnamed alarms can only be listed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    metric_id: String,
+    aggregation: QueryAggregation,
+    /// Zero aggregates the whole lookback in a single window
+    #[serde(default)]
+    window_secs: f32,
+    /// Seconds between evaluations, the manager's default interval if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    interval_secs: Option<f32>,
+    /// Seconds of data queried by each evaluation, by default the interval or the window if
+    /// longer, so every evaluation sees at least a whole window
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lookback_secs: Option<f32>,
+    /// Seconds an evaluation may take before its query is cancelled, the interval if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timeout_secs: Option<f32>,
+    /// Compared with the aggregated value, `>` by default
+    #[serde(default)]
+    operator: Operator,
+    /// Critical threshold
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    limit: Option<f32>,
+    /// Bounds of the `inside` and `outside` operators
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    range: Option<(f32, f32)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    warning: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    warning_range: Option<(f32, f32)>,
+    /// Threshold the value must go past for an active alarm to clear
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    recovery: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    recovery_range: Option<(f32, f32)>,
+    /// Makes this an absence alarm, which fires when the metric got no samples for this many
+    /// seconds. Absence alarms count the samples and have no thresholds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    absent_secs: Option<f32>,
+    /// Applied when there are no samples. Count alarms count zero instead, so they can't set it
+    #[serde(default)]
+    no_data: NoDataPolicy,
+    /// Seconds the condition must hold before the alarm fires
+    #[serde(default)]
+    for_secs: f32,
+    /// Notifiers of this alarm, instead of the global ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    notifiers: Option<Vec<NotifierConfig>>,
+    /// Muted alarms are evaluated without notifying their transitions
+    #[serde(default)]
+    muted: bool,
+    /// Labels matched by silences
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+}
+
+/// An alarm with the state of its last evaluation
+struct Alarm {
+    /// Identifies the alarm in the schedule, kept while it is evaluated the same
+    id: u64,
+    config: AlarmConfig,
+    thresholds: Thresholds,
+    status: AlarmStatus,
+    notifiers: Vec<Arc<NotifierQueue>>,
+    last_evaluation: Option<EvaluationStats>,
+    /// Whether the alarm triggered because there was no data
+    no_data: bool,
+    /// Severity the notifiers were last told the alarm fires with, `None` once told it resolved.
+    /// Behind the state while the alarm is silenced or muted
+    notified: Option<Severity>,
+}
+
+/// Alarms of the alarm file, reloaded when it changes
+struct AlarmSet {
+    alarm_file: String,
+    /// Modification time of the loaded alarm file
+    modified: Option<SystemTime>,
+    alarms: Vec<Alarm>,
+    /// Lines of the alarm file that were skipped, written back as they were when saving
+    skipped: Vec<String>,
+    global_notifiers: Vec<Arc<NotifierQueue>>,
+    /// Every notifier used by an alarm, shared by the alarms with the same configuration
+    notifiers: Vec<Arc<NotifierQueue>>,
+    next_id: u64,
+    silences: SilenceSet,
+}
+
+pub struct AlarmManager {
+    /// Runs the scheduler, which hands the due alarms to `evaluation_pool`
+    pool: ThreadPool,
+    evaluation_pool: ThreadPool,
+    alarms: Arc<Mutex<AlarmSet>>,
+    /// Interval of the alarms that don't configure one
+    default_interval: Duration,
+}
+
+impl AlarmConfig {
+    fn thresholds(&self) -> Result<Thresholds, String> {
+        if self.absent_secs.is_some() {
+            let thresholds = [self.limit, self.warning, self.recovery];
+            let ranges = [self.range, self.warning_range, self.recovery_range];
+            if thresholds.iter().any(Option::is_some) || ranges.iter().any(Option::is_some) {
+                return Err("absence alarms have no thresholds".to_string());
+            }
+            return Thresholds::new(Condition::Compare(Operator::Less, 1.0), None, None);
+        }
+        let condition = |limit: Option<f32>, range: Option<(f32, f32)>| {
+            if limit.is_none() && range.is_none() {
+                return Ok(None);
+            }
+            Condition::new(self.operator, limit, range).map(Some)
+        };
+        Thresholds::new(
+            Condition::new(self.operator, self.limit, self.range)?,
+            condition(self.warning, self.warning_range)?,
+            condition(self.recovery, self.recovery_range)?,
+        )
+    }
+
+    fn pending_for(&self) -> Duration {
+        seconds(self.for_secs)
+    }
+
+    fn interval(&self, default: Duration) -> Duration {
+        self.interval_secs.map(seconds).unwrap_or(default)
+    }
+
+    fn lookback(&self, default_interval: Duration) -> Duration {
+        match (self.absent_secs, self.lookback_secs) {
+            (Some(absent_secs), _) => seconds(absent_secs),
+            (None, Some(lookback_secs)) => seconds(lookback_secs),
+            (None, None) => self.interval(default_interval).max(seconds(self.window_secs)),
+        }
+    }
+
+    fn timeout(&self, default_interval: Duration) -> Duration {
+        self.timeout_secs.map(seconds).unwrap_or_else(|| self.interval(default_interval))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.aggregation.validate()?;
+        let outside = |secs: Option<f32>, min: f32, max: f32| secs.is_some_and(|secs| !(min..=max).contains(&secs));
+        if outside(self.interval_secs, 1.0, MAX_INTERVAL_SECS) {
+            return Err(format!("the interval must be between 1 and {} seconds", MAX_INTERVAL_SECS));
+        }
+        if self.lookback_secs.is_some_and(|secs| secs <= 0.0) || outside(self.lookback_secs, 0.0, MAX_LOOKBACK_SECS) {
+            return Err(format!("the lookback must be positive and at most {} seconds", MAX_LOOKBACK_SECS));
+        }
+        if outside(Some(self.window_secs), 0.0, MAX_LOOKBACK_SECS) {
+            return Err(format!("the window must be between 0 and {} seconds", MAX_LOOKBACK_SECS));
+        }
+        if outside(self.timeout_secs, 0.001, MAX_INTERVAL_SECS) {
+            return Err(format!("the timeout must be between 1ms and {} seconds", MAX_INTERVAL_SECS));
+        }
+        if outside(Some(self.for_secs), 0.0, MAX_LOOKBACK_SECS) {
+            return Err(format!("the condition must hold for between 0 and {} seconds", MAX_LOOKBACK_SECS));
+        }
+        if outside(self.absent_secs, 1.0, MAX_LOOKBACK_SECS) {
+            return Err(format!("the absence must last between 1 and {} seconds", MAX_LOOKBACK_SECS));
+        }
+        if self.absent_secs.is_some() && self.aggregation != QueryAggregation::Count {
+            return Err("absence alarms need the Count aggregation".to_string());
+        }
+        if self.aggregation == QueryAggregation::Count && self.no_data != NoDataPolicy::Ok {
+            return Err("count alarms see no data as a count of zero".to_string());
+        }
+        if self.absent_secs.is_some() && self.lookback_secs.is_some() {
+            return Err("absence alarms look back as long as the absence".to_string());
+        }
+        Ok(())
+    }
+
+    fn query_params(&self, default_interval: Duration) -> QueryParams {
+        QueryParams {
+            metric_id: self.metric_id.clone(),
+            selector: MetricSelector::Exact,
+            combine: false,
+            date_range: Some(TimeRange::last(self.lookback(default_interval))),
+            aggregation: self.aggregation.clone(),
+            window_secs: if self.absent_secs.is_some() { 0.0 } else { self.window_secs },
+            ranking: None,
+            timeout_ms: Some(self.timeout(default_interval).num_milliseconds() as u64),
+            partial: false,
+        }
+    }
+}
+
+impl Alarm {
+    /// Updates the state with the values of an evaluation, notifying the transitions unless
+    /// the alarm is silenced by `silence`
+    fn evaluate(&mut self, mut values: Vec<f32>, silence: Option<&str>) {
+        let config = &self.config;
+        debug!("[ALARM] {:?} has {:?}: {:?} (thresholds: {:?})", config.metric_id, config.aggregation, values, self.thresholds);
+        let pending_for = config.pending_for();
+        let no_data = values.is_empty();
+        if no_data && config.aggregation == QueryAggregation::Count {
+            values.push(0.0);
+        } else if no_data && config.no_data == NoDataPolicy::Keep {
+            debug!("No data for alarm of {:?}, keeping it {:?}", config.metric_id, self.status.state);
+            self.catch_up(None, silence);
+            return;
+        } else if no_data && config.no_data == NoDataPolicy::Alert {
+            let changed = self.status.update(Some(Severity::Critical), pending_for, Utc::now());
+            self.no_data = true;
+            if let Some(state) = changed {
+                self.notify(state, None, silence);
+            }
+            self.catch_up(None, silence);
+            return;
+        }
+        // The latest window with the highest severity is the one notified
+        let current = self.status.active_severity();
+        let breach = values
+            .into_iter()
+            .filter_map(|value| Some((self.thresholds.severity(value, current)?, value)))
+            .max_by_key(|(severity, _)| *severity);
+        let severity = breach.map(|(severity, _)| severity);
+        let changed = self.status.update(severity, pending_for, Utc::now());
+        // A resolved alarm is notified with what made it trigger
+        if self.status.state != AlarmState::Resolved {
+            self.no_data = false;
+        }
+        let value = breach.map(|(_, value)| value);
+        if let Some(state) = changed {
+            self.notify(state, value, silence);
+        }
+        self.catch_up(value, silence);
+    }
+
+    /// Notifies the current state if the transitions to it weren't, as the alarm was silenced
+    /// or muted then
+    fn catch_up(&mut self, value: Option<f32>, silence: Option<&str>) {
+        let firing = self.status.severity.filter(|_| self.status.state == AlarmState::Firing);
+        if self.config.muted || silence.is_some() || self.notified == firing {
+            return;
+        }
+        let state = if firing.is_some() { AlarmState::Firing } else { AlarmState::Resolved };
+        info!("Alarm for {:?} is {:?} since its notifications were suppressed", self.config.metric_id, state);
+        self.notify(state, value, silence);
+    }
+
+    fn notify(&mut self, state: AlarmState, value: Option<f32>, silence: Option<&str>) {
+        let config = &self.config;
+        let severity = self.status.severity.unwrap_or(Severity::Critical);
+        let condition = match (state, config.absent_secs) {
+            (AlarmState::Pending | AlarmState::Ok, _) => {
+                info!("Alarm for {:?} is now {:?}", config.metric_id, state);
+                return;
+            }
+            _ if self.no_data => "no data".to_string(),
+            (_, Some(absent_secs)) => format!("no samples for {}s", absent_secs),
+            (AlarmState::Firing, None) => self.thresholds.condition(severity).to_string(),
+            (AlarmState::Resolved, None) => self.thresholds.active_condition(severity).to_string(),
+        };
+        if config.muted {
+            info!("Alarm for {:?} is now {:?}, not notified as it is muted", config.metric_id, state);
+            return;
+        }
+        if let Some(silence) = silence {
+            info!("Alarm for {:?} is now {:?}, not notified as it is silenced by {:?}", config.metric_id, state, silence);
+            return;
+        }
+        // The count of an absence alarm is always zero
+        let value = value.filter(|_| config.absent_secs.is_none());
+        let event = AlarmEvent {
+            name: config.name.clone(),
+            metric_id: config.metric_id.clone(),
+            aggregation: config.aggregation.clone(),
+            state,
+            severity: self.status.severity,
+            condition,
+            value,
+            timestamp: self.status.since,
+        };
+        for notifier in &self.notifiers {
+            notifier.send(event.clone());
+        }
+        self.notified = self.status.severity.filter(|_| state == AlarmState::Firing);
+    }
+}
+
+impl AlarmSet {
+    fn load(alarm_file: String, notifier_file: &str, silence_file: String) -> io::Result<Self> {
+        let mut set = Self {
+            alarm_file,
+            modified: None,
+            alarms: vec![],
+            skipped: vec![],
+            global_notifiers: vec![],
+            notifiers: vec![],
+            next_id: 0,
+            silences: SilenceSet::load(silence_file)?,
+        };
+        for config in NotifierConfig::load_all(notifier_file)? {
+            match notifier_queue(&mut set.notifiers, &config) {
+                Ok(queue) => set.global_notifiers.push(queue),
+                Err(e) => warn!("Skipping notifier {:?}: {}", config, e),
+            }
+        }
+        set.modified = modification_time(&set.alarm_file);
+        (set.alarms, set.skipped) = set.read()?;
+        Ok(set)
+    }
+
+    /// Reloads the alarms if the alarm file changed since it was loaded, or if `force` is set,
+    /// and the silences likewise. Alarms with the same configuration as before keep their state
+    fn reload(&mut self, force: bool) {
+        self.silences.reload(force);
+        let modified = modification_time(&self.alarm_file);
+        if !force && modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+        let mut alarms = match self.read() {
+            Ok((alarms, skipped)) => {
+                self.skipped = skipped;
+                alarms
+            }
+            Err(e) => {
+                error!("Couldn't reload alarms from {}, keeping the current ones: {}", self.alarm_file, e);
+                return;
+            }
+        };
+        let mut kept = 0;
+        for alarm in &mut alarms {
+            let previous = self.alarms.iter().position(|previous| previous.config == alarm.config);
+            if let Some(idx) = previous {
+                let previous = self.alarms.swap_remove(idx);
+                alarm.id = previous.id;
+                alarm.status = previous.status;
+                alarm.last_evaluation = previous.last_evaluation;
+                alarm.no_data = previous.no_data;
+                alarm.notified = previous.notified;
+                kept += 1;
+            }
+        }
+        info!("Reloaded {} alarms from {}, {} unchanged", alarms.len(), self.alarm_file, kept);
+        self.alarms = alarms;
+    }
+
+    /// Reads the alarm file. Invalid lines are reported and returned apart from the alarms
+    fn read(&mut self) -> io::Result<(Vec<Alarm>, Vec<String>)> {
+        let file = File::open(&self.alarm_file)?;
+        let reader = BufReader::new(file);
+        let mut alarms = vec![];
+        let mut skipped = vec![];
+        for (idx, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let alarm = serde_json::from_str::<AlarmConfig>(&line)
+                .map_err(|e| e.to_string())
+                .and_then(|config| self.build(config));
+            match alarm {
+                Ok(alarm) if alarm.config.name.is_some() && find(&alarms, &alarm.config.name).is_some() => {
+                    warn!("Skipping alarm at {}:{}: the name is already used", self.alarm_file, idx + 1);
+                    skipped.push(line);
+                }
+                Ok(alarm) => alarms.push(alarm),
+                Err(e) => {
+                    error!("Skipping alarm at {}:{}: {}", self.alarm_file, idx + 1, e);
+                    skipped.push(line);
+                }
+            }
+        }
+        debug!("Loaded alarms: {:?}", alarms.iter().map(|alarm| &alarm.config).collect::<Vec<_>>());
+        Ok((alarms, skipped))
+    }
+
+    fn build(&mut self, config: AlarmConfig) -> Result<Alarm, String> {
+        config.validate()?;
+        let thresholds = config.thresholds()?;
+        // An alarm isn't notified elsewhere when one of its own notifiers is broken
+        let notifiers = match &config.notifiers {
+            Some(configs) => configs
+                .iter()
+                .map(|notifier| {
+                    notifier_queue(&mut self.notifiers, notifier)
+                        .map_err(|e| format!("its notifier {:?} can't be used: {}", notifier, e))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            None => self.global_notifiers.clone(),
+        };
+        self.next_id += 1;
+        Ok(Alarm {
+            id: self.next_id,
+            config,
+            thresholds,
+            status: AlarmStatus::new(Utc::now()),
+            notifiers,
+            last_evaluation: None,
+            no_data: false,
+            notified: None,
+        })
+    }
+
+    fn lag_stats(&self) -> LagStats {
+        LagStats::new(self.alarms.iter().filter_map(|alarm| alarm.last_evaluation.as_ref()))
+    }
+
+    fn list(&self) -> Vec<AlarmSummary> {
+        self.alarms
+            .iter()
+            .map(|alarm| AlarmSummary {
+                config: alarm.config.clone(),
+                state: alarm.status.state,
+                severity: alarm.status.severity,
+                since: alarm.status.since,
+                last_evaluation: alarm.last_evaluation.clone(),
+            })
+            .collect()
+    }
+
+    fn add(&mut self, config: AlarmConfig) -> io::Result<()> {
+        if config.name.is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Alarms added over the wire need a name"));
+        }
+        // Notifiers run commands and write files, so only the alarm file may configure them
+        if config.notifiers.is_some() {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Notifiers can only be set in the alarm file"));
+        }
+        if find(&self.alarms, &config.name).is_some() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "There is already an alarm with that name"));
+        }
+        let alarm = self.build(config).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.alarms.push(alarm);
+        self.save()
+    }
+
+    /// Replaces the alarm with the name of `config`. Its state is kept if nothing but its
+    /// notifications changed
+    fn update(&mut self, config: AlarmConfig) -> io::Result<()> {
+        let idx = self.position(&config.name)?;
+        if config.notifiers != self.alarms[idx].config.notifiers {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Notifiers can only be changed in the alarm file"));
+        }
+        let mut alarm = self.build(config).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let previous = &self.alarms[idx];
+        let evaluated_the_same = AlarmConfig {
+            notifiers: previous.config.notifiers.clone(),
+            muted: previous.config.muted,
+            ..alarm.config.clone()
+        } == previous.config;
+        if evaluated_the_same {
+            alarm.id = previous.id;
+            alarm.status = previous.status;
+            alarm.last_evaluation = previous.last_evaluation.clone();
+            alarm.no_data = previous.no_data;
+            alarm.notified = previous.notified;
+        }
+        self.alarms[idx] = alarm;
+        self.save()
+    }
+
+    fn delete(&mut self, name: &str) -> io::Result<()> {
+        let idx = self.position(&Some(name.to_string()))?;
+        self.alarms.remove(idx);
+        self.save()
+    }
+
+    fn silence(&mut self, name: &str, muted: bool) -> io::Result<()> {
+        let idx = self.position(&Some(name.to_string()))?;
+        self.alarms[idx].config.muted = muted;
+        self.save()
+    }
+
+    fn get(&self, id: u64) -> Option<&Alarm> {
+        self.alarms.iter().find(|alarm| alarm.id == id)
+    }
+
+    fn get_mut(&mut self, id: u64) -> Option<&mut Alarm> {
+        self.alarms.iter_mut().find(|alarm| alarm.id == id)
+    }
+
+    fn position(&self, name: &Option<String>) -> io::Result<usize> {
+        if name.is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The alarm needs a name"));
+        }
+        find(&self.alarms, name).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "There is no alarm with that name"))
+    }
+
+    /// Replaces the alarm file with the current alarms, followed by the lines that were skipped
+    /// so they can still be fixed. The file is replaced atomically, and isn't reloaded as it
+    /// already matches the alarms
+    fn save(&mut self) -> io::Result<()> {
+        let tmp_path = format!("{}.tmp", self.alarm_file);
+        let mut file = File::create(&tmp_path)?;
+        for alarm in &self.alarms {
+            serde_json::to_writer(&mut file, &alarm.config)?;
+            file.write_all(b"\n")?;
+        }
+        for line in &self.skipped {
+            file.write_all(line.as_bytes())?;
+            file.write_all(b"\n")?;
+        }
+        file.sync_all()?;
+        std::fs::rename(tmp_path, &self.alarm_file)?;
+        self.modified = modification_time(&self.alarm_file);
+        Ok(())
+    }
+}
+
+impl AlarmManager {
+    /// Loads the alarms, the global notifiers from `notifier_file` and the silences from
+    /// `silence_file`. Alarms without an interval are evaluated every `default_interval`
+    pub fn from_file(
+        alarm_file: String,
+        notifier_file: String,
+        silence_file: String,
+        default_interval: Duration,
+    ) -> io::Result<Self> {
+        let pool = ThreadPool::new(1);
+        let evaluation_pool = ThreadPool::new(EVALUATION_POOL_SIZE);
+        let alarms = Arc::new(Mutex::new(AlarmSet::load(alarm_file, &notifier_file, silence_file)?));
+        Ok( Self {pool, evaluation_pool, alarms, default_interval} )
+    }
+
+    /// Handle to manage the alarms over the wire, for clients sending `token`. Without a token
+    /// the alarms can only be managed through the alarm and silence files
+    pub fn admin(&self, token: Option<String>) -> AlarmAdmin {
+        AlarmAdmin {
+            alarms: self.alarms.clone(),
+            token: token.filter(|token| !token.is_empty()),
+        }
+    }
+
+    /// Evaluates each alarm on its own interval until `term_flag` is set, up to
+    /// `EVALUATION_POOL_SIZE` at the same time. The alarm file is reloaded when it changes, or
+    /// when `reload_flag` is set
+    pub fn start(
+        &mut self,
+        router: Arc<ShardRouter>,
+        query_senders: Vec<Sender<Query>>,
+        term_flag: Arc<AtomicBool>,
+        reload_flag: Arc<AtomicBool>,
+    ) {
+        let alarms = self.alarms.clone();
+        let evaluation_pool = self.evaluation_pool.clone();
+        let default_interval = self.default_interval;
+        let query_senders = Arc::new(query_senders);
+        self.pool.execute(move || {
+            let mut schedule = Schedule::default();
+            // Evaluations send back when their alarm is due again, or `None` if it was deleted
+            let (done_sender, done_recv) = channel::<(u64, Option<Instant>)>();
+            while !term_flag.load(Ordering::Relaxed) {
+                {
+                    let mut alarms = alarms.lock().unwrap();
+                    alarms.reload(reload_flag.swap(false, Ordering::Relaxed));
+                    // New alarms, and the ones changed since they were scheduled, are due now
+                    let now = Instant::now();
+                    for alarm in &alarms.alarms {
+                        if !schedule.contains(alarm.id) {
+                            schedule.add(alarm.id, now);
+                        }
+                    }
+                }
+                while let Some((due, id)) = schedule.pop_due(Instant::now()) {
+                    let alarms = alarms.clone();
+                    let router = router.clone();
+                    let query_senders = query_senders.clone();
+                    let term_flag = term_flag.clone();
+                    let done_sender = done_sender.clone();
+                    evaluation_pool.execute(move || {
+                        let next_due = evaluate(&alarms, &router, &query_senders, &term_flag, id, due, default_interval);
+                        done_sender.send((id, next_due)).ok();
+                    });
+                }
+                let now = Instant::now();
+                let wake_up = schedule.next_due().map_or(now + SCHEDULE_TICK, |due| due.min(now + SCHEDULE_TICK));
+                match done_recv.recv_timeout(wake_up.saturating_duration_since(now)) {
+                    Ok(done) => {
+                        for (id, next_due) in std::iter::once(done).chain(done_recv.try_iter()) {
+                            match next_due {
+                                Some(next_due) => schedule.reschedule(id, next_due),
+                                None => schedule.remove(id),
+                            }
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => unreachable!("the scheduler holds a sender"),
+                }
+            }
+            // Running evaluations have their queries cancelled
+            evaluation_pool.join();
+            let notifiers = alarms.lock().unwrap().notifiers.clone();
+            for notifier in notifiers {
+                notifier.join();
+            }
+        })
+    }
+
+    pub fn stop(&self) {
+        self.pool.join()
+    }
+}
+
+/// Evaluates an alarm due at `due`, returning when it is due again or `None` if it no longer
+/// exists. The alarms aren't locked while querying, so results of changed alarms are dropped
+fn evaluate(
+    alarms: &Mutex<AlarmSet>,
+    router: &ShardRouter,
+    query_senders: &[Sender<Query>],
+    term_flag: &Arc<AtomicBool>,
+    id: u64,
+    due: Instant,
+    default_interval: Duration,
+) -> Option<Instant> {
+    let started = Instant::now();
+    let config = alarms.lock().unwrap().get(id)?.config.clone();
+    let lag = started.saturating_duration_since(due);
+    let interval = config.interval(default_interval).to_std().unwrap();
+    let overdue = lag > interval;
+    if overdue {
+        warn!("Alarm for {:?} was evaluated {:?} late", config.metric_id, lag);
+    }
+    let query_params = config.query_params(default_interval);
+    debug!("Querying alarm {:?}", query_params);
+    let terminating = term_flag.clone();
+    let result = run_query(router, query_senders, query_params, move || terminating.load(Ordering::Relaxed));
+    if term_flag.load(Ordering::Relaxed) {
+        return None;
+    }
+    let mut alarms = alarms.lock().unwrap();
+    let silence = alarms.silences.silencing(&config, Utc::now()).map(|silence| silence.name().to_string());
+    let silence = silence.as_deref();
+    let alarm = alarms.get_mut(id)?;
+    if alarm.config == config {
+        let error = match result {
+            Ok(QueryResult::Values(values)) => {
+                alarm.evaluate(values, silence);
+                None
+            }
+            Ok(QueryResult::PerMetric(_) | QueryResult::Ranking(_)) => {
+                alarm.evaluate(vec![], silence);
+                None
+            }
+            Err(e) => {
+                // The state is kept until the alarm can be evaluated again
+                error!("Couldn't evaluate alarm for {:?}: {}", config.metric_id, e);
+                Some(e.to_string())
+            }
+        };
+        alarm.last_evaluation = Some(EvaluationStats {
+            at: Utc::now(),
+            lag_ms: lag.as_millis() as u64,
+            duration_ms: started.elapsed().as_millis() as u64,
+            overdue,
+            error,
+        });
+    }
+    // A late evaluation doesn't make the following ones catch up
+    let interval = alarm.config.interval(default_interval).to_std().unwrap();
+    Some((due + interval).max(Instant::now()))
+}
+
+fn seconds(secs: f32) -> Duration {
+    Duration::milliseconds((secs * 1000.0) as i64)
+}
+
+fn find(alarms: &[Alarm], name: &Option<String>) -> Option<usize> {
+    alarms.iter().position(|alarm| name.is_some() && alarm.config.name == *name)
+}
+
+pub(super) fn modification_time(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Queue of a notifier configuration, reusing the one in `queues` with the same configuration
+fn notifier_queue(queues: &mut Vec<Arc<NotifierQueue>>, config: &NotifierConfig) -> io::Result<Arc<NotifierQueue>> {
+    if let Some(queue) = queues.iter().find(|queue| queue.config() == config) {
+        return Ok(queue.clone());
+    }
+    let queue = Arc::new(NotifierQueue::new(config.clone())?);
+    queues.push(queue.clone());
+    Ok(queue)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_path;
+
+    fn config(line: &str) -> AlarmConfig {
+        serde_json::from_str(line).unwrap()
+    }
+
+    /// Loads the alarms of `lines` from `alarm_file`, without notifier and silence files
+    pub(super) fn load_set(alarm_file: &str, lines: &[&str]) -> io::Result<AlarmSet> {
+        std::fs::write(alarm_file, lines.iter().map(|line| format!("{}\n", line)).collect::<String>()).unwrap();
+        AlarmSet::load(alarm_file.to_string(), &temp_path("missing"), temp_path("missing"))
+    }
+
+    #[test]
+    fn alarms_with_broken_notifiers_are_rejected() {
+        let alarm_file = temp_path("alarm-broken-notifiers.jsonl");
+        let valid = r#"{"name":"a","metric_id":"cpu","aggregation":"Avg","limit":90}"#;
+        let broken = r#"{"name":"b","metric_id":"cpu","aggregation":"Avg","limit":90,"notifiers":[{"Webhook":{"url":"https://example.com"}}]}"#;
+        let set = load_set(&alarm_file, &[valid, broken]);
+        std::fs::remove_file(&alarm_file).unwrap();
+        let mut set = set.unwrap();
+        let names = set.alarms.iter().map(|alarm| alarm.config.name.as_deref()).collect::<Vec<_>>();
+        assert_eq!(names, [Some("a")]);
+        let Err(e) = set.build(config(broken)) else {
+            panic!("the alarm was built without its notifier");
+        };
+        assert!(e.starts_with("its notifier Webhook"), "{}", e);
+    }
+
+    /// Events notified to the file of an alarm built from `line`
+    fn notified_states(name: &str, line: &str, evaluations: impl FnOnce(&mut Alarm)) -> Vec<String> {
+        let alarm_file = temp_path(&format!("alarm-{}.jsonl", name));
+        let events = temp_path(&format!("alarm-{}-events.jsonl", name));
+        let set = load_set(&alarm_file, &[]);
+        std::fs::remove_file(&alarm_file).unwrap();
+        let mut config = config(line);
+        config.notifiers = Some(vec![NotifierConfig::File { path: events.clone() }]);
+        let mut alarm = set.unwrap().build(config).unwrap();
+        evaluations(&mut alarm);
+        alarm.notifiers[0].join();
+        let contents = std::fs::read_to_string(&events).unwrap_or_default();
+        std::fs::remove_file(&events).ok();
+        contents
+            .lines()
+            .map(|line| {
+                let event = serde_json::from_str::<serde_json::Value>(line).unwrap();
+                format!("{} {}", event["state"].as_str().unwrap(), event["severity"])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn alarms_firing_when_their_silence_ends_are_notified() {
+        let line = r#"{"metric_id":"cpu","aggregation":"Avg","limit":90,"warning":80}"#;
+        let states = notified_states("silence-ends", line, |alarm| {
+            alarm.evaluate(vec![95.0], Some("maintenance"));
+            alarm.evaluate(vec![95.0], Some("maintenance"));
+            assert_eq!(alarm.status.state, AlarmState::Firing);
+            alarm.evaluate(vec![95.0], None);
+            alarm.evaluate(vec![95.0], None);
+            // The severity changed while silenced
+            alarm.evaluate(vec![85.0], Some("maintenance"));
+            alarm.evaluate(vec![85.0], None);
+        });
+        assert_eq!(states, [r#"Firing "Critical""#, r#"Firing "Warning""#]);
+    }
+
+    #[test]
+    fn alarms_resolved_while_muted_are_notified_when_unmuted() {
+        let line = r#"{"metric_id":"cpu","aggregation":"Avg","limit":90}"#;
+        let states = notified_states("unmuted", line, |alarm| {
+            alarm.evaluate(vec![95.0], None);
+            alarm.config.muted = true;
+            alarm.evaluate(vec![10.0], None);
+            alarm.evaluate(vec![10.0], None);
+            assert_eq!(alarm.status.state, AlarmState::Ok);
+            alarm.config.muted = false;
+            alarm.evaluate(vec![10.0], None);
+            alarm.evaluate(vec![10.0], None);
+        });
+        assert_eq!(states, [r#"Firing "Critical""#, r#"Resolved "Critical""#]);
+    }
+
+    #[test]
+    fn alarms_firing_only_while_silenced_are_not_notified() {
+        let line = r#"{"metric_id":"cpu","aggregation":"Avg","limit":90}"#;
+        let states = notified_states("only-silenced", line, |alarm| {
+            alarm.evaluate(vec![95.0], Some("maintenance"));
+            alarm.evaluate(vec![10.0], Some("maintenance"));
+            alarm.evaluate(vec![10.0], None);
+        });
+        assert!(states.is_empty(), "{:?}", states);
+    }
+
+    #[test]
+    fn intervals_lookbacks_and_absences_are_capped() {
+        let base = r#""metric_id":"cpu","aggregation":"Count","limit":1"#;
+        let valid = |fields: &str| config(&format!("{{{},{}}}", base, fields)).validate();
+        valid(r#""interval_secs":86400,"lookback_secs":604800,"window_secs":604800,"timeout_secs":86400"#).unwrap();
+        valid(r#""absent_secs":604800"#).unwrap();
+        valid(r#""for_secs":604800"#).unwrap();
+        for fields in [
+            r#""interval_secs":86401"#,
+            r#""interval_secs":0.5"#,
+            r#""lookback_secs":604801"#,
+            r#""lookback_secs":0"#,
+            r#""window_secs":1e12"#,
+            r#""timeout_secs":1e9"#,
+            r#""absent_secs":604801"#,
+            r#""for_secs":-1"#,
+            r#""for_secs":604801"#,
+        ] {
+            assert!(valid(fields).is_err(), "{} was accepted", fields);
+        }
+    }
+
+    #[test]
+    fn count_alarms_have_no_no_data_policy() {
+        let valid = |line: &str| config(line).validate();
+        valid(r#"{"metric_id":"cpu","aggregation":"Count","limit":1,"no_data":"ok"}"#).unwrap();
+        valid(r#"{"metric_id":"cpu","aggregation":"Avg","limit":1,"no_data":"keep"}"#).unwrap();
+        for line in [
+            r#"{"metric_id":"cpu","aggregation":"Count","limit":1,"no_data":"keep"}"#,
+            r#"{"metric_id":"cpu","aggregation":"Count","absent_secs":60,"no_data":"alert"}"#,
+        ] {
+            assert!(valid(line).is_err(), "{} was accepted", line);
+        }
+    }
+
+    #[test]
+    fn saving_keeps_the_skipped_lines() {
+        let alarm_file = temp_path("alarm-skipped-lines.jsonl");
+        let valid = r#"{"name":"a","metric_id":"cpu","aggregation":"Avg","limit":90}"#;
+        let invalid = r#"{"name":"b","metric_id":"cpu","aggregation":"Avg","limt":90}"#;
+        let duplicate = r#"{"name":"a","metric_id":"mem","aggregation":"Max","limit":1}"#;
+        let mut set = load_set(&alarm_file, &[valid, invalid, "not json", duplicate]).unwrap();
+        let added = set.add(config(r#"{"name":"c","metric_id":"disk","aggregation":"Max","limit":1}"#));
+        let contents = std::fs::read_to_string(&alarm_file).unwrap();
+        std::fs::remove_file(&alarm_file).unwrap();
+        added.unwrap();
+        let lines = contents.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[2..], [invalid, "not json", duplicate]);
+        assert_eq!(config(lines[1]).name.as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn notifiers_cant_be_set_over_the_wire() {
+        let alarm_file = temp_path("alarm-wire-notifiers.jsonl");
+        let configured = r#"{"name":"a","metric_id":"cpu","aggregation":"Avg","limit":90,"notifiers":["Stdout"]}"#;
+        let mut set = load_set(&alarm_file, &[configured]).unwrap();
+        let command = r#"[{"Command":{"program":"sh","args":["-c","echo pwned"]}}]"#;
+        let added = set.add(config(&format!(
+            r#"{{"name":"b","metric_id":"cpu","aggregation":"Avg","limit":90,"notifiers":{}}}"#,
+            command
+        )));
+        let changed = set.update(config(&format!(
+            r#"{{"name":"a","metric_id":"cpu","aggregation":"Avg","limit":90,"notifiers":{}}}"#,
+            command
+        )));
+        // Other changes of alarms with their own notifiers are fine
+        let kept = set.update(config(&configured.replace("90", "95")));
+        std::fs::remove_file(&alarm_file).unwrap();
+        assert_eq!(added.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(changed.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        kept.unwrap();
+        assert_eq!(set.alarms.len(), 1);
+        assert_eq!(set.alarms[0].config.limit, Some(95.0));
+    }
+}
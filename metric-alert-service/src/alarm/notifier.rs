@@ -0,0 +1,490 @@
+use crate::alarm::condition::Severity;
+use crate::alarm::state::AlarmState;
+use crate::metric::query::QueryAggregation;
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::Command;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use threadpool::ThreadPool;
+
+/// Attempts of a webhook after the first one, if not configured
+const DEFAULT_WEBHOOK_RETRIES: u32 = 3;
+/// Wait before the first retry, doubled after each one up to `MAX_WEBHOOK_BACKOFF`
+const WEBHOOK_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_WEBHOOK_BACKOFF: Duration = Duration::from_secs(30);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time a command has to exit before it is killed, if not configured
+const DEFAULT_COMMAND_TIMEOUT_SECS: f32 = 30.0;
+const MAX_COMMAND_TIMEOUT_SECS: f32 = 600.0;
+/// How often a running command is checked for exit
+const COMMAND_POLL: Duration = Duration::from_millis(10);
+
+/// A notified transition of an alarm
+#[derive(Clone, Debug, Serialize)]
+pub struct AlarmEvent {
+    pub name: Option<String>,
+    pub metric_id: String,
+    pub aggregation: QueryAggregation,
+    pub state: AlarmState,
+    pub severity: Option<Severity>,
+    /// Condition that fired the alarm, or that stopped holding when it resolved
+    pub condition: String,
+    pub value: Option<f32>,
+    pub timestamp: DateTime<Utc>,
+}
+
+pub trait Notifier: Send + Sync {
+    fn notify(&self, event: &AlarmEvent) -> io::Result<()>;
+}
+
+/// Where alarm events are sent. Configured globally in the notifier file, one per line, or for
+/// a single alarm in its `notifiers`
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub enum NotifierConfig {
+    /// Prints events to the standard output, used when no notifier is configured
+    Stdout,
+    /// POSTs events as JSON to an `http://` URL, retrying with exponential backoff
+    Webhook {
+        url: String,
+        #[serde(default)]
+        retries: Option<u32>,
+    },
+    /// Appends events to a file, one JSON per line
+    File { path: String },
+    /// Runs a command with the event in `ALARM_*` environment variables, killing it if it
+    /// doesn't exit in time
+    Command {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default)]
+        timeout_secs: Option<f32>,
+    },
+}
+
+/// Sends events to a notifier from its own thread, so slow notifiers don't delay evaluations and
+/// each notifier gets the events in order
+pub struct NotifierQueue {
+    config: NotifierConfig,
+    notifier: Arc<dyn Notifier>,
+    pool: ThreadPool,
+}
+
+struct StdoutNotifier;
+
+struct WebhookNotifier {
+    host: String,
+    path: String,
+    retries: u32,
+    backoff: Duration,
+}
+
+struct FileNotifier {
+    path: String,
+}
+
+struct CommandNotifier {
+    program: String,
+    args: Vec<String>,
+    timeout: Duration,
+}
+
+impl NotifierConfig {
+    /// Loads the global notifiers, one per line. Without a notifier file events are printed
+    pub fn load_all(path: &str) -> io::Result<Vec<Self>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![NotifierConfig::Stdout]),
+            Err(e) => return Err(e),
+        };
+        let mut configs = vec![];
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(config) => configs.push(config),
+                Err(e) => warn!("Skipping invalid notifier {:?}: {}", line, e),
+            }
+        }
+        Ok(configs)
+    }
+
+    fn build(&self) -> io::Result<Arc<dyn Notifier>> {
+        Ok(match self {
+            NotifierConfig::Stdout => Arc::new(StdoutNotifier),
+            NotifierConfig::Webhook { url, retries } => Arc::new(WebhookNotifier::new(url, *retries)?),
+            NotifierConfig::File { path } => Arc::new(FileNotifier { path: path.clone() }),
+            NotifierConfig::Command {
+                program,
+                args,
+                timeout_secs,
+            } => {
+                let timeout_secs = timeout_secs.unwrap_or(DEFAULT_COMMAND_TIMEOUT_SECS);
+                if !(0.001..=MAX_COMMAND_TIMEOUT_SECS).contains(&timeout_secs) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("The timeout must be between 1ms and {} seconds", MAX_COMMAND_TIMEOUT_SECS),
+                    ));
+                }
+                Arc::new(CommandNotifier {
+                    program: program.clone(),
+                    args: args.clone(),
+                    timeout: Duration::from_secs_f32(timeout_secs),
+                })
+            }
+        })
+    }
+}
+
+impl NotifierQueue {
+    pub fn new(config: NotifierConfig) -> io::Result<Self> {
+        Ok(Self {
+            notifier: config.build()?,
+            config,
+            pool: ThreadPool::new(1),
+        })
+    }
+
+    pub fn config(&self) -> &NotifierConfig {
+        &self.config
+    }
+
+    pub fn send(&self, event: AlarmEvent) {
+        let notifier = self.notifier.clone();
+        let config = self.config.clone();
+        self.pool.execute(move || {
+            if let Err(e) = notifier.notify(&event) {
+                error!("Couldn't notify {:?} with {:?}: {}", event.metric_id, config, e);
+            }
+        });
+    }
+
+    /// Waits for the queued events to be sent
+    pub fn join(&self) {
+        self.pool.join()
+    }
+}
+
+impl Notifier for StdoutNotifier {
+    fn notify(&self, event: &AlarmEvent) -> io::Result<()> {
+        let severity = event.severity.unwrap_or(Severity::Critical);
+        match event.state {
+            AlarmState::Resolved => println!(
+                "[RESOLVED] [{}] {:?} no longer has {:?} {}",
+                severity, event.metric_id, event.aggregation, event.condition
+            ),
+            _ if event.value.is_none() => {
+                println!("[ALARM] [{}] {:?} has {}", severity, event.metric_id, event.condition)
+            }
+            _ => println!(
+                "[ALARM] [{}] {:?} has {:?} {}: {}",
+                severity,
+                event.metric_id,
+                event.aggregation,
+                event.condition,
+                event.value.unwrap_or(f32::NAN)
+            ),
+        }
+        Ok(())
+    }
+}
+
+impl WebhookNotifier {
+    fn new(url: &str, retries: Option<u32>) -> io::Result<Self> {
+        let Some(address) = url.strip_prefix("http://") else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Only http:// webhooks are supported"));
+        };
+        let (host, path) = match address.find('/') {
+            Some(idx) => address.split_at(idx),
+            None => (address, "/"),
+        };
+        let host = if host.contains(':') { host.to_string() } else { format!("{}:80", host) };
+        Ok(Self {
+            host,
+            path: path.to_string(),
+            retries: retries.unwrap_or(DEFAULT_WEBHOOK_RETRIES),
+            backoff: WEBHOOK_BACKOFF,
+        })
+    }
+
+    fn post(&self, body: &[u8]) -> io::Result<()> {
+        let address = self
+            .host
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Unknown webhook host"))?;
+        let mut stream = TcpStream::connect_timeout(&address, WEBHOOK_TIMEOUT)?;
+        stream.set_read_timeout(Some(WEBHOOK_TIMEOUT))?;
+        stream.set_write_timeout(Some(WEBHOOK_TIMEOUT))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.host,
+            body.len()
+        )?;
+        stream.write_all(body)?;
+        let mut status_line = String::new();
+        BufReader::new(stream.take(1024)).read_line(&mut status_line)?;
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            _ => Err(io::Error::other(format!("Webhook answered {:?}", status_line.trim()))),
+        }
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify(&self, event: &AlarmEvent) -> io::Result<()> {
+        let body = serde_json::to_vec(event)?;
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            match self.post(&body) {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < self.retries => {
+                    warn!("Webhook {}{} failed, retrying in {:?}: {}", self.host, self.path, backoff, e);
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_WEBHOOK_BACKOFF);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Notifier for FileNotifier {
+    fn notify(&self, event: &AlarmEvent) -> io::Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        // A single write per event, so lines of concurrent writers don't interleave
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&line)
+    }
+}
+
+impl Notifier for CommandNotifier {
+    fn notify(&self, event: &AlarmEvent) -> io::Result<()> {
+        let optional = |value: Option<String>| value.unwrap_or_default();
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .env("ALARM_EVENT", serde_json::to_string(event)?)
+            .env("ALARM_METRIC_ID", &event.metric_id)
+            .env("ALARM_AGGREGATION", format!("{:?}", event.aggregation))
+            .env("ALARM_STATE", format!("{:?}", event.state))
+            .env("ALARM_SEVERITY", optional(event.severity.map(|severity| severity.to_string())))
+            .env("ALARM_CONDITION", &event.condition)
+            .env("ALARM_VALUE", optional(event.value.map(|value| value.to_string())))
+            .env("ALARM_TIMESTAMP", event.timestamp.to_rfc3339())
+            .spawn()?;
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                child.kill()?;
+                child.wait()?;
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("{} didn't exit within {:?}", self.program, self.timeout),
+                ));
+            }
+            std::thread::sleep(COMMAND_POLL);
+        };
+        if !status.success() {
+            return Err(io::Error::other(format!("{} exited with {}", self.program, status)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_path;
+    use chrono::TimeZone;
+    use std::net::TcpListener;
+
+    fn event() -> AlarmEvent {
+        AlarmEvent {
+            name: Some("cpu_high".to_string()),
+            metric_id: "cpu".to_string(),
+            aggregation: QueryAggregation::Avg,
+            state: AlarmState::Firing,
+            severity: Some(Severity::Warning),
+            condition: "> 90".to_string(),
+            value: Some(95.5),
+            timestamp: Utc.timestamp(1_650_000_000, 0),
+        }
+    }
+
+
+    /// Answers each request with the next status, returning the bodies and when they arrived
+    fn webhook_stub(statuses: &'static [u16]) -> (String, std::thread::JoinHandle<Vec<(Instant, String)>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks/alarms", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let mut requests = vec![];
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                assert_eq!(request_line, "POST /hooks/alarms HTTP/1.1\r\n");
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header == "\r\n" {
+                        break;
+                    }
+                    if let Some(length) = header.strip_prefix("Content-Length: ") {
+                        content_length = length.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                requests.push((Instant::now(), String::from_utf8(body).unwrap()));
+                write!(reader.get_mut(), "HTTP/1.1 {} Status\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    fn webhook(url: &str, retries: u32, backoff: Duration) -> WebhookNotifier {
+        WebhookNotifier {
+            backoff,
+            ..WebhookNotifier::new(url, Some(retries)).unwrap()
+        }
+    }
+
+    #[test]
+    fn webhooks_retry_failed_posts_after_the_backoff() {
+        let (url, stub) = webhook_stub(&[500, 200]);
+        let backoff = Duration::from_millis(50);
+        webhook(&url, 2, backoff).notify(&event()).unwrap();
+        let requests = stub.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].0 - requests[0].0 >= backoff);
+        let body: serde_json::Value = serde_json::from_str(&requests[1].1).unwrap();
+        assert_eq!(body, serde_json::to_value(event()).unwrap());
+        assert_eq!(body["metric_id"], "cpu");
+        assert_eq!(body["state"], "Firing");
+        assert_eq!(body["severity"], "Warning");
+    }
+
+    #[test]
+    fn webhooks_fail_once_out_of_retries() {
+        let (url, stub) = webhook_stub(&[500, 503]);
+        let Err(e) = webhook(&url, 1, Duration::from_millis(1)).notify(&event()) else {
+            panic!("the webhook kept failing");
+        };
+        assert!(e.to_string().contains("503"), "{}", e);
+        assert_eq!(stub.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn webhooks_need_http_urls() {
+        assert!(WebhookNotifier::new("https://example.com/hook", None).is_err());
+        let webhook = WebhookNotifier::new("http://example.com", None).unwrap();
+        assert_eq!((webhook.host.as_str(), webhook.path.as_str()), ("example.com:80", "/"));
+        assert_eq!(webhook.retries, DEFAULT_WEBHOOK_RETRIES);
+    }
+
+    #[test]
+    fn file_notifiers_append_a_json_line_per_event() {
+        let path = temp_path("notifier-events.jsonl");
+        let notifier = FileNotifier { path: path.clone() };
+        notifier.notify(&event()).unwrap();
+        notifier.notify(&AlarmEvent { state: AlarmState::Resolved, ..event() }).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines = contents.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        let states = lines
+            .iter()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["state"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(states, ["Firing", "Resolved"]);
+    }
+
+    #[test]
+    fn commands_get_the_event_in_the_environment() {
+        let path = temp_path("notifier-command.env");
+        let notifier = CommandNotifier {
+            program: "sh".to_string(),
+            args: vec!["-c".to_string(), format!("env | grep ^ALARM_ | sort > {}", path)],
+            timeout: Duration::from_secs(5),
+        };
+        notifier.notify(&event()).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let env = contents.lines().filter(|line| !line.starts_with("ALARM_EVENT=")).collect::<Vec<_>>();
+        assert_eq!(
+            env,
+            [
+                "ALARM_AGGREGATION=Avg",
+                "ALARM_CONDITION=> 90",
+                "ALARM_METRIC_ID=cpu",
+                "ALARM_SEVERITY=WARNING",
+                "ALARM_STATE=Firing",
+                "ALARM_TIMESTAMP=2022-04-15T05:20:00+00:00",
+                "ALARM_VALUE=95.5",
+            ]
+        );
+        let json = contents.lines().find_map(|line| line.strip_prefix("ALARM_EVENT=")).unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(json).unwrap(), serde_json::to_value(event()).unwrap());
+    }
+
+    #[test]
+    fn failing_commands_are_errors() {
+        let notifier = CommandNotifier {
+            program: "false".to_string(),
+            args: vec![],
+            timeout: Duration::from_secs(5),
+        };
+        assert!(notifier.notify(&event()).is_err());
+    }
+
+    #[test]
+    fn commands_are_killed_after_their_timeout() {
+        let notifier = CommandNotifier {
+            program: "sleep".to_string(),
+            args: vec!["10".to_string()],
+            timeout: Duration::from_millis(200),
+        };
+        let start = Instant::now();
+        let e = notifier.notify(&event()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn command_timeouts_are_capped() {
+        let command = |timeout_secs| NotifierConfig::Command {
+            program: "true".to_string(),
+            args: vec![],
+            timeout_secs: Some(timeout_secs),
+        };
+        assert!(command(1.0).build().is_ok());
+        for timeout_secs in [0.0, -1.0, f32::NAN, 1e9] {
+            let Err(e) = command(timeout_secs).build() else {
+                panic!("timeout {} was accepted", timeout_secs);
+            };
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
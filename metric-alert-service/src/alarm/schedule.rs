@@ -0,0 +1,165 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::time::Instant;
+
+/// Evaluations of alarms ordered by when they are due. Alarms are identified by their id, and
+/// are either waiting in the queue or being evaluated, never both
+#[derive(Default)]
+pub struct Schedule {
+    queue: BinaryHeap<Reverse<(Instant, u64)>>,
+    /// Alarms waiting in the queue or being evaluated
+    scheduled: HashSet<u64>,
+}
+
+/// How the last evaluation of an alarm went
+#[derive(Clone, Debug, Serialize)]
+pub struct EvaluationStats {
+    pub at: DateTime<Utc>,
+    /// Time the evaluation started after it was due
+    pub lag_ms: u64,
+    pub duration_ms: u64,
+    /// Whether the evaluation started more than an interval after it was due
+    pub overdue: bool,
+    /// Why the alarm couldn't be evaluated, its state is kept meanwhile
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Lag of the last evaluation of every alarm, as listed to clients
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct LagStats {
+    /// Alarms evaluated at least once
+    pub evaluated: usize,
+    pub max_lag_ms: u64,
+    pub p95_lag_ms: u64,
+    /// Alarms whose last evaluation started more than an interval after it was due
+    pub overdue: usize,
+}
+
+impl Schedule {
+    pub fn contains(&self, id: u64) -> bool {
+        self.scheduled.contains(&id)
+    }
+
+    /// Queues an alarm that isn't scheduled yet
+    pub fn add(&mut self, id: u64, due: Instant) {
+        if self.scheduled.insert(id) {
+            self.queue.push(Reverse((due, id)));
+        }
+    }
+
+    /// Queues again an alarm once its evaluation finished
+    pub fn reschedule(&mut self, id: u64, due: Instant) {
+        if self.scheduled.contains(&id) {
+            self.queue.push(Reverse((due, id)));
+        }
+    }
+
+    /// Forgets an alarm that no longer exists
+    pub fn remove(&mut self, id: u64) {
+        self.scheduled.remove(&id);
+    }
+
+    /// Takes the next evaluation if it is due at `now`. The alarm stays scheduled until it is
+    /// rescheduled or removed
+    pub fn pop_due(&mut self, now: Instant) -> Option<(Instant, u64)> {
+        match self.queue.peek() {
+            Some(Reverse((due, _))) if *due <= now => self.queue.pop().map(|Reverse(entry)| entry),
+            _ => None,
+        }
+    }
+
+    pub fn next_due(&self) -> Option<Instant> {
+        self.queue.peek().map(|Reverse((due, _))| *due)
+    }
+}
+
+impl LagStats {
+    pub fn new<'a>(evaluations: impl Iterator<Item = &'a EvaluationStats>) -> Self {
+        let mut lags = vec![];
+        let mut overdue = 0;
+        for evaluation in evaluations {
+            lags.push(evaluation.lag_ms);
+            overdue += usize::from(evaluation.overdue);
+        }
+        lags.sort_unstable();
+        // Nearest rank, so the figure is a lag some alarm actually had
+        let p95_lag_ms = match lags.len() {
+            0 => 0,
+            len => lags[(len * 95).div_ceil(100) - 1],
+        };
+        Self {
+            evaluated: lags.len(),
+            max_lag_ms: lags.last().copied().unwrap_or(0),
+            p95_lag_ms,
+            overdue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn evaluation(lag_ms: u64, overdue: bool) -> EvaluationStats {
+        EvaluationStats {
+            at: Utc::now(),
+            lag_ms,
+            duration_ms: 0,
+            overdue,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn due_alarms_are_taken_in_order_until_rescheduled() {
+        let now = Instant::now();
+        let mut schedule = Schedule::default();
+        schedule.add(1, now + Duration::from_secs(2));
+        schedule.add(2, now);
+        schedule.add(2, now + Duration::from_secs(5));
+        assert_eq!(schedule.pop_due(now), Some((now, 2)));
+        assert_eq!(schedule.pop_due(now), None);
+        assert_eq!(schedule.next_due(), Some(now + Duration::from_secs(2)));
+        // Being evaluated, the alarm isn't added again
+        assert!(schedule.contains(2));
+        schedule.add(2, now);
+        assert_eq!(schedule.pop_due(now), None);
+        schedule.reschedule(2, now + Duration::from_secs(1));
+        assert_eq!(schedule.pop_due(now + Duration::from_secs(3)), Some((now + Duration::from_secs(1), 2)));
+        assert_eq!(schedule.pop_due(now + Duration::from_secs(3)), Some((now + Duration::from_secs(2), 1)));
+    }
+
+    #[test]
+    fn removed_alarms_are_not_rescheduled() {
+        let now = Instant::now();
+        let mut schedule = Schedule::default();
+        schedule.add(1, now);
+        schedule.pop_due(now);
+        schedule.remove(1);
+        schedule.reschedule(1, now);
+        assert!(!schedule.contains(1));
+        assert_eq!(schedule.next_due(), None);
+    }
+
+    #[test]
+    fn lag_stats_take_the_nearest_rank() {
+        assert_eq!(LagStats::new([].iter()), LagStats::default());
+        let evaluations = (1..=40).map(|lag_ms| evaluation(lag_ms * 10, lag_ms > 38)).collect::<Vec<_>>();
+        let stats = LagStats::new(evaluations.iter());
+        assert_eq!(
+            stats,
+            LagStats {
+                evaluated: 40,
+                max_lag_ms: 400,
+                p95_lag_ms: 380,
+                overdue: 2,
+            }
+        );
+        let single = [evaluation(7, false)];
+        assert_eq!(LagStats::new(single.iter()).p95_lag_ms, 7);
+    }
+}
@@ -0,0 +1,348 @@
+use crate::alarm::cron::CronSchedule;
+use crate::alarm::{modification_time, AlarmConfig};
+use chrono::{DateTime, Duration, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::time::SystemTime;
+
+/// Longest maintenance window, so finding whether one is active stays cheap
+const MAX_WINDOW_SECS: f32 = 7.0 * 24.0 * 3600.0;
+
+/// Suppresses the notifications of the alarms it matches while it is active. The alarms are
+/// still evaluated, but their transitions meanwhile aren't notified
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Silence {
+    /// Identifies the silence when managed over the wire
+    name: String,
+    /// Matches the alarm with this name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    alarm: Option<String>,
+    /// Matches the alarms of the metrics starting with this prefix
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metric_prefix: Option<String>,
+    /// Matches the alarms with this tag
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
+    /// Set to when the silence was added if missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    start: Option<DateTime<Utc>>,
+    /// Recurring silences without an end last until they are deleted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    end: Option<DateTime<Utc>>,
+    /// Makes the silence a recurring maintenance window, lasting `duration_secs` from every
+    /// minute matching this cron expression
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(with = "cron_expression")]
+    cron: Option<CronSchedule>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    duration_secs: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+}
+
+/// A silence as listed to clients
+#[derive(Debug, Serialize)]
+pub struct SilenceSummary {
+    #[serde(flatten)]
+    pub silence: Silence,
+    pub active: bool,
+}
+
+/// Silences of the silence file, which is reloaded when it changes
+pub(super) struct SilenceSet {
+    silence_file: String,
+    modified: Option<SystemTime>,
+    silences: Vec<Silence>,
+    /// Lines of the silence file that were skipped, written back as they were when saving
+    skipped: Vec<String>,
+}
+
+impl Silence {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.alarm.is_none() && self.metric_prefix.is_none() && self.tag.is_none() {
+            return Err("the silence needs an alarm, metric_prefix or tag to match".to_string());
+        }
+        if let (Some(start), Some(end)) = (self.start, self.end) {
+            if start >= end {
+                return Err("the silence must end after it starts".to_string());
+            }
+        }
+        match (&self.cron, self.duration_secs) {
+            (Some(_), Some(secs)) if secs.is_nan() || !(60.0..=MAX_WINDOW_SECS).contains(&secs) => Err(format!(
+                "the duration of a maintenance window must be between 60 and {} seconds",
+                MAX_WINDOW_SECS
+            )),
+            (Some(_), Some(_)) => Ok(()),
+            (Some(_), None) => Err("the maintenance window needs a duration_secs".to_string()),
+            (None, Some(_)) => Err("only maintenance windows have a duration, with a cron".to_string()),
+            (None, None) if self.end.is_none() => Err("the silence needs an end".to_string()),
+            (None, None) => Ok(()),
+        }
+    }
+
+    fn matches(&self, config: &AlarmConfig) -> bool {
+        self.alarm.as_ref().is_none_or(|alarm| config.name.as_ref() == Some(alarm))
+            && self.metric_prefix.as_ref().is_none_or(|prefix| config.metric_id.starts_with(prefix))
+            && self.tag.as_ref().is_none_or(|tag| config.tags.contains(tag))
+    }
+
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        if self.start.is_some_and(|start| now < start) || self.ended(now) {
+            return false;
+        }
+        let (Some(cron), Some(duration_secs)) = (&self.cron, self.duration_secs) else {
+            return true;
+        };
+        // Only the latest window started by now may still be open
+        let duration = Duration::seconds(duration_secs as i64);
+        cron.latest(now, now - duration).is_some_and(|start| start + duration > now)
+    }
+
+    fn ended(&self, now: DateTime<Utc>) -> bool {
+        self.end.is_some_and(|end| end <= now)
+    }
+}
+
+impl SilenceSet {
+    /// Loads the silences, there are none if the file doesn't exist
+    pub fn load(silence_file: String) -> io::Result<Self> {
+        let mut set = Self {
+            silence_file,
+            modified: None,
+            silences: vec![],
+            skipped: vec![],
+        };
+        set.refresh()?;
+        Ok(set)
+    }
+
+    /// Reloads the silences if the file changed since it was loaded, or if `force` is set
+    pub fn reload(&mut self, force: bool) {
+        let modified = modification_time(&self.silence_file);
+        if !force && modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+        match self.read() {
+            Ok((silences, skipped)) => {
+                info!("Reloaded {} silences from {}", silences.len(), self.silence_file);
+                self.silences = silences;
+                self.skipped = skipped;
+            }
+            Err(e) => error!("Couldn't reload silences from {}, keeping the current ones: {}", self.silence_file, e),
+        }
+    }
+
+    /// Reads the silences again, so changes to the silence file aren't overwritten when saving
+    fn refresh(&mut self) -> io::Result<()> {
+        self.modified = modification_time(&self.silence_file);
+        (self.silences, self.skipped) = self.read()?;
+        Ok(())
+    }
+
+    /// Reads the silence file. Invalid lines are reported and returned apart from the silences
+    fn read(&self) -> io::Result<(Vec<Silence>, Vec<String>)> {
+        let file = match File::open(&self.silence_file) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((vec![], vec![])),
+            Err(e) => return Err(e),
+        };
+        let mut silences: Vec<Silence> = vec![];
+        let mut skipped = vec![];
+        for (idx, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let silence = serde_json::from_str::<Silence>(&line)
+                .map_err(|e| e.to_string())
+                .and_then(|silence| silence.validate().map(|_| silence));
+            match silence {
+                Ok(silence) if silences.iter().any(|other| other.name == silence.name) => {
+                    warn!("Skipping silence at {}:{}: the name is already used", self.silence_file, idx + 1);
+                    skipped.push(line);
+                }
+                Ok(silence) => silences.push(silence),
+                Err(e) => {
+                    warn!("Skipping silence at {}:{}: {}", self.silence_file, idx + 1, e);
+                    skipped.push(line);
+                }
+            }
+        }
+        Ok((silences, skipped))
+    }
+
+    /// Active silence matching an alarm
+    pub fn silencing(&self, config: &AlarmConfig, now: DateTime<Utc>) -> Option<&Silence> {
+        self.silences
+            .iter()
+            .find(|silence| silence.matches(config) && silence.is_active(now))
+    }
+
+    pub fn list(&self) -> Vec<SilenceSummary> {
+        let now = Utc::now();
+        self.silences
+            .iter()
+            .map(|silence| SilenceSummary {
+                silence: silence.clone(),
+                active: silence.is_active(now),
+            })
+            .collect()
+    }
+
+    pub fn add(&mut self, mut silence: Silence) -> io::Result<()> {
+        let now = Utc::now();
+        silence.start.get_or_insert(now);
+        silence.validate().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if silence.ended(now) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The silence already ended"));
+        }
+        self.refresh()?;
+        if self.silences.iter().any(|other| other.name == silence.name) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "There is already a silence with that name"));
+        }
+        self.silences.push(silence);
+        self.save()
+    }
+
+    pub fn delete(&mut self, name: &str) -> io::Result<()> {
+        self.refresh()?;
+        let Some(idx) = self.silences.iter().position(|silence| silence.name == name) else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "There is no silence with that name"));
+        };
+        self.silences.remove(idx);
+        self.save()
+    }
+
+    /// Replaces the silence file with the silences that didn't end yet, followed by the lines
+    /// that were skipped. The file is replaced atomically, and isn't reloaded as it already
+    /// matches the silences
+    fn save(&mut self) -> io::Result<()> {
+        let now = Utc::now();
+        self.silences.retain(|silence| !silence.ended(now));
+        let tmp_path = format!("{}.tmp", self.silence_file);
+        let mut file = File::create(&tmp_path)?;
+        for silence in &self.silences {
+            serde_json::to_writer(&mut file, silence)?;
+            file.write_all(b"\n")?;
+        }
+        for line in &self.skipped {
+            file.write_all(line.as_bytes())?;
+            file.write_all(b"\n")?;
+        }
+        file.sync_all()?;
+        std::fs::rename(tmp_path, &self.silence_file)?;
+        self.modified = modification_time(&self.silence_file);
+        Ok(())
+    }
+}
+
+/// Cron expressions are written as strings
+mod cron_expression {
+    use crate::alarm::cron::CronSchedule;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(cron: &Option<CronSchedule>, serializer: S) -> Result<S::Ok, S::Error> {
+        cron.as_ref().map(CronSchedule::to_string).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<CronSchedule>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|expression| CronSchedule::parse(&expression).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_path;
+    use chrono::TimeZone;
+
+    fn silence(json: &str) -> Silence {
+        let silence = serde_json::from_str::<Silence>(json).unwrap();
+        silence.validate().unwrap();
+        silence
+    }
+
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.ymd(2022, 4, day).and_hms(hour, minute, 0)
+    }
+
+    #[test]
+    fn silences_are_active_between_their_start_and_end() {
+        let silence = silence(r#"{"name":"s","tag":"db","start":"2022-04-15T10:00:00Z","end":"2022-04-15T12:00:00Z"}"#);
+        assert!(!silence.is_active(at(15, 9, 59)));
+        assert!(silence.is_active(at(15, 10, 0)));
+        assert!(silence.is_active(at(15, 11, 59)));
+        assert!(!silence.is_active(at(15, 12, 0)));
+    }
+
+    #[test]
+    fn maintenance_windows_last_their_duration_from_each_match() {
+        // Every day at 23:30 for an hour
+        let silence = silence(r#"{"name":"s","tag":"db","cron":"30 23 * * *","duration_secs":3600}"#);
+        assert!(!silence.is_active(at(15, 23, 29)));
+        assert!(silence.is_active(at(15, 23, 30)));
+        assert!(silence.is_active(at(16, 0, 29)));
+        assert!(!silence.is_active(at(16, 0, 30)));
+    }
+
+    #[test]
+    fn maintenance_windows_can_span_days() {
+        // From Friday at 20:00 for three days, 2022-04-15 being a Friday
+        let silence = silence(r#"{"name":"s","tag":"db","cron":"0 20 * * 5","duration_secs":259200}"#);
+        assert!(!silence.is_active(at(15, 19, 59)));
+        assert!(silence.is_active(at(15, 20, 0)));
+        assert!(silence.is_active(at(18, 19, 59)));
+        assert!(!silence.is_active(at(18, 20, 0)));
+        assert!(!silence.is_active(at(21, 12, 0)));
+    }
+
+    #[test]
+    fn silences_match_their_alarms() {
+        let config = |json: &str| serde_json::from_str::<AlarmConfig>(json).unwrap();
+        let alarm = config(r#"{"name":"db_latency","metric_id":"db.latency","aggregation":"Avg","tags":["db"]}"#);
+        let matches = |json: &str| silence(json).matches(&alarm);
+        assert!(matches(r#"{"name":"s","alarm":"db_latency","end":"2022-04-15T12:00:00Z"}"#));
+        assert!(matches(r#"{"name":"s","metric_prefix":"db.","tag":"db","end":"2022-04-15T12:00:00Z"}"#));
+        assert!(!matches(r#"{"name":"s","metric_prefix":"db.","tag":"web","end":"2022-04-15T12:00:00Z"}"#));
+        assert!(!matches(r#"{"name":"s","alarm":"db","end":"2022-04-15T12:00:00Z"}"#));
+    }
+
+    #[test]
+    fn saving_keeps_the_skipped_lines() {
+        let path = temp_path("silences-skipped.jsonl");
+        let invalid = r#"{"name":"a","tag":"db"}"#;
+        let duplicate = r#"{"name":"b","tag":"web","end":"2099-01-01T00:00:00Z"}"#;
+        std::fs::write(&path, format!("{}\n{}\nnot json\n{}\n", invalid, duplicate, duplicate)).unwrap();
+        let mut set = SilenceSet::load(path.clone()).unwrap();
+        set.add(silence(r#"{"name":"c","tag":"db","end":"2099-01-01T00:00:00Z"}"#)).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines = contents.lines().collect::<Vec<_>>();
+        assert_eq!(lines[2..], [invalid, "not json", duplicate]);
+    }
+
+    #[test]
+    fn changes_since_the_last_reload_are_kept() {
+        let path = temp_path("silences-changed.jsonl");
+        std::fs::write(&path, "").unwrap();
+        let mut set = SilenceSet::load(path.clone()).unwrap();
+        std::fs::write(&path, r#"{"name":"a","tag":"db","end":"2099-01-01T00:00:00Z"}"#).unwrap();
+        set.add(silence(r#"{"name":"b","tag":"db","end":"2099-01-01T00:00:00Z"}"#)).unwrap();
+        let added_twice = set.add(silence(r#"{"name":"a","tag":"web","end":"2099-01-01T00:00:00Z"}"#));
+        let names = set.list().into_iter().map(|summary| summary.silence.name).collect::<Vec<_>>();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(added_twice.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(names, ["a", "b"]);
+    }
+}
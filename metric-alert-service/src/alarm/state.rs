@@ -0,0 +1,156 @@
+use crate::alarm::condition::Severity;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+/// States of an alarm. Only the transitions to `Firing` and `Resolved` are notified, along with
+/// severity changes while firing
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum AlarmState {
+    /// The condition doesn't hold
+    Ok,
+    /// The condition holds, but not for long enough to fire
+    Pending,
+    Firing,
+    /// The condition stopped holding after firing, the alarm goes back to `Ok` on the next
+    /// evaluation where it still doesn't hold
+    Resolved,
+}
+
+/// State of an alarm and when it was entered
+#[derive(Clone, Copy, Debug)]
+pub struct AlarmStatus {
+    pub state: AlarmState,
+    pub since: DateTime<Utc>,
+    /// Severity of the last evaluation where the alarm triggered
+    pub severity: Option<Severity>,
+}
+
+impl AlarmStatus {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            state: AlarmState::Ok,
+            since: now,
+            severity: None,
+        }
+    }
+
+    /// Severity of the alarm while it is pending or firing
+    pub fn active_severity(&self) -> Option<Severity> {
+        match self.state {
+            AlarmState::Pending | AlarmState::Firing => self.severity,
+            AlarmState::Ok | AlarmState::Resolved => None,
+        }
+    }
+
+    /// Moves to the state following an evaluation at `now` where the alarm triggered with
+    /// `severity`, returning the new state if it or the severity of a firing alarm changed. The
+    /// alarm fires once it triggered for `pending_for`
+    pub fn update(
+        &mut self,
+        severity: Option<Severity>,
+        pending_for: Duration,
+        now: DateTime<Utc>,
+    ) -> Option<AlarmState> {
+        let previous = self.severity;
+        if severity.is_some() {
+            self.severity = severity;
+        }
+        let next = match (self.state, severity.is_some()) {
+            (AlarmState::Ok | AlarmState::Resolved, true) if pending_for <= Duration::zero() => {
+                AlarmState::Firing
+            }
+            (AlarmState::Ok | AlarmState::Resolved, true) => AlarmState::Pending,
+            (AlarmState::Pending, true) if now - self.since >= pending_for => AlarmState::Firing,
+            (AlarmState::Pending, false) | (AlarmState::Resolved, false) => AlarmState::Ok,
+            (AlarmState::Firing, false) => AlarmState::Resolved,
+            (state, _) => state,
+        };
+        if next == self.state {
+            return (next == AlarmState::Firing && previous != self.severity).then_some(next);
+        }
+        self.state = next;
+        self.since = now;
+        Some(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp(1_650_000_000 + secs, 0)
+    }
+
+    const CRITICAL: Option<Severity> = Some(Severity::Critical);
+    const WARNING: Option<Severity> = Some(Severity::Warning);
+
+    #[test]
+    fn fires_after_triggering_for_the_pending_time() {
+        let pending_for = Duration::seconds(60);
+        let mut status = AlarmStatus::new(at(0));
+        assert_eq!(status.update(None, pending_for, at(10)), None);
+        assert_eq!(status.update(CRITICAL, pending_for, at(20)), Some(AlarmState::Pending));
+        assert_eq!(status.update(CRITICAL, pending_for, at(60)), None);
+        assert_eq!(status.active_severity(), CRITICAL);
+        assert_eq!(status.update(CRITICAL, pending_for, at(80)), Some(AlarmState::Firing));
+        assert_eq!(status.since, at(80));
+        assert_eq!(status.update(CRITICAL, pending_for, at(90)), None);
+    }
+
+    #[test]
+    fn pending_alarms_that_stop_triggering_go_back_to_ok() {
+        let pending_for = Duration::seconds(60);
+        let mut status = AlarmStatus::new(at(0));
+        status.update(CRITICAL, pending_for, at(0));
+        assert_eq!(status.update(None, pending_for, at(30)), Some(AlarmState::Ok));
+        assert_eq!(status.active_severity(), None);
+        // The pending time starts over
+        assert_eq!(status.update(CRITICAL, pending_for, at(40)), Some(AlarmState::Pending));
+        assert_eq!(status.update(CRITICAL, pending_for, at(70)), None);
+    }
+
+    #[test]
+    fn resolves_and_then_goes_back_to_ok() {
+        let mut status = AlarmStatus::new(at(0));
+        assert_eq!(status.update(CRITICAL, Duration::zero(), at(0)), Some(AlarmState::Firing));
+        assert_eq!(status.update(None, Duration::zero(), at(10)), Some(AlarmState::Resolved));
+        assert_eq!(status.active_severity(), None);
+        assert_eq!(status.update(None, Duration::zero(), at(20)), Some(AlarmState::Ok));
+        assert_eq!(status.update(None, Duration::zero(), at(30)), None);
+    }
+
+    #[test]
+    fn resolved_alarms_fire_again_after_the_pending_time() {
+        let pending_for = Duration::seconds(30);
+        let mut status = AlarmStatus::new(at(0));
+        status.update(CRITICAL, pending_for, at(0));
+        status.update(CRITICAL, pending_for, at(30));
+        assert_eq!(status.update(None, pending_for, at(40)), Some(AlarmState::Resolved));
+        assert_eq!(status.update(CRITICAL, pending_for, at(50)), Some(AlarmState::Pending));
+        assert_eq!(status.update(CRITICAL, pending_for, at(80)), Some(AlarmState::Firing));
+    }
+
+    #[test]
+    fn severity_changes_are_reported_while_firing() {
+        let mut status = AlarmStatus::new(at(0));
+        assert_eq!(status.update(WARNING, Duration::zero(), at(0)), Some(AlarmState::Firing));
+        assert_eq!(status.update(WARNING, Duration::zero(), at(10)), None);
+        assert_eq!(status.update(CRITICAL, Duration::zero(), at(20)), Some(AlarmState::Firing));
+        assert_eq!(status.active_severity(), CRITICAL);
+        assert_eq!(status.update(WARNING, Duration::zero(), at(30)), Some(AlarmState::Firing));
+        // Changes don't restart the firing time
+        assert_eq!(status.since, at(0));
+    }
+
+    #[test]
+    fn severity_changes_while_pending_are_not_reported() {
+        let pending_for = Duration::seconds(60);
+        let mut status = AlarmStatus::new(at(0));
+        status.update(WARNING, pending_for, at(0));
+        assert_eq!(status.update(CRITICAL, pending_for, at(10)), None);
+        assert_eq!(status.update(CRITICAL, pending_for, at(60)), Some(AlarmState::Firing));
+        assert_eq!(status.active_severity(), CRITICAL);
+    }
+}
@@ -6,7 +6,8 @@ use std::io;
 use std::io::{stdin, BufRead, BufReader};
 use std::net::TcpStream;
 use std::time::Duration;
-use tp1::metric::MetricAction;
+use tp1::alarm::admin::AlarmAction;
+use tp1::metric::{parser, MetricAction};
 
 #[derive(Envconfig)]
 struct Config {
@@ -31,6 +32,9 @@ struct Config {
     /// repeat automated actions after N millis
     #[envconfig(from = "REPEAT_TIME", default = "100")]
     repeat_time: u32,
+    /// token sent to manage the alarms, the ADMIN_TOKEN of the server
+    #[envconfig(from = "ADMIN_TOKEN", default = "")]
+    admin_token: String,
 }
 
 fn main() {
@@ -41,15 +45,38 @@ fn main() {
     run_client(env_config).unwrap();
 }
 
+const ALARM_USAGE: &str = "usage: client alarm list | add <json> | update <json> | delete <name> | silence <name> | unsilence <name> | stats | silences list | silences add <json> | silences delete <name>";
+
 fn run_client(config: Config) -> io::Result<()> {
     let host_addr = format!("{}:{}", config.server_host, config.server_port);
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if let Some(command) = args.first() {
+        let action = match command.as_str() {
+            "alarm" => parse_alarm_command(&args[1..]).map(|action| MetricAction::Alarm {
+                token: config.admin_token.clone(),
+                action,
+            }),
+            _ => Err(format!("unknown command {:?}", command)),
+        };
+        return match action {
+            Ok(action) => do_request(&host_addr, &action),
+            Err(e) => {
+                println!("{}\n{}", e, ALARM_USAGE);
+                Ok(())
+            }
+        };
+    }
     if config.interactive {
-        for line in stdin().lock().lines().flatten() {
-            if let Ok(action) = serde_json::from_str::<MetricAction>(line.as_str()) {
-                if let Err(e) = do_request(&host_addr, &action) {
-                    println!("Couldn't execute action: {}", e);
-                    break;
+        for line in stdin().lock().lines() {
+            let line = line?;
+            match parse_action(&line) {
+                Ok(action) => {
+                    if let Err(e) = do_request(&host_addr, &action) {
+                        println!("Couldn't execute action: {}", e);
+                        break;
+                    }
                 }
+                Err(e) => println!("Couldn't parse action: {}", e),
             }
         }
     } else {
@@ -57,8 +84,15 @@ fn run_client(config: Config) -> io::Result<()> {
         let reader = BufReader::new(file);
         let actions = reader
             .lines()
-            .flatten()
-            .flat_map(|line| serde_json::from_str::<MetricAction>(&line))
+            .collect::<io::Result<Vec<_>>>()?
+            .into_iter()
+            .filter_map(|line| match parse_action(&line) {
+                Ok(action) => Some(action),
+                Err(e) => {
+                    warn!("Skipping action: {}", e);
+                    None
+                }
+            })
             .collect::<Vec<_>>();
         if actions.is_empty() {
             warn!("No metrics loaded, aborting");
@@ -89,14 +123,54 @@ fn run_client(config: Config) -> io::Result<()> {
     Ok(())
 }
 
+/// Actions are written either as JSON or in the text query language
+fn parse_action(line: &str) -> Result<MetricAction, String> {
+    if line.trim_start().starts_with('{') {
+        return serde_json::from_str::<MetricAction>(line)
+            .map_err(|e| format!("invalid JSON action: {}", e));
+    }
+    parser::parse(line).map_err(|e| e.highlight(line))
+}
+
+/// Alarm subcommands, alarms being written as in the alarm file
+fn parse_alarm_command(args: &[String]) -> Result<AlarmAction, String> {
+    let config = |json: &str| serde_json::from_str(json).map_err(|e| format!("invalid alarm: {}", e));
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["list"] => Ok(AlarmAction::List),
+        ["add", json] => Ok(AlarmAction::Add(config(json)?)),
+        ["update", json] => Ok(AlarmAction::Update(config(json)?)),
+        ["delete", name] => Ok(AlarmAction::Delete(name.to_string())),
+        ["silence", name] => Ok(AlarmAction::Silence {
+            name: name.to_string(),
+            muted: true,
+        }),
+        ["unsilence", name] => Ok(AlarmAction::Silence {
+            name: name.to_string(),
+            muted: false,
+        }),
+        ["stats"] => Ok(AlarmAction::Stats),
+        ["silences", "list"] => Ok(AlarmAction::ListSilences),
+        ["silences", "add", json] => serde_json::from_str(json)
+            .map(AlarmAction::AddSilence)
+            .map_err(|e| format!("invalid silence: {}", e)),
+        ["silences", "delete", name] => Ok(AlarmAction::DeleteSilence(name.to_string())),
+        _ => Err("invalid alarm command".to_string()),
+    }
+}
+
 fn do_request(host_addr: &String, action: &MetricAction) -> io::Result<()> {
     info!("Connecting to {}", host_addr);
     let mut connection = TcpStream::connect(host_addr)?;
     action.write_to(&mut connection)?;
     let reader = BufReader::new(connection);
-    if let Some(response) = reader.lines().flatten().next() {
+    let mut answered = false;
+    // Streamed results are sent in several lines until the server closes the connection
+    for response in reader.lines() {
+        let response = response?;
         println!("Server response: {}", response);
-    } else {
+        answered = true;
+    }
+    if !answered {
         println!("Server didn't answer");
     }
     Ok(())
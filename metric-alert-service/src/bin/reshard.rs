@@ -0,0 +1,28 @@
+use envconfig::Envconfig;
+use log::info;
+use tp1::metric::reshard::reshard;
+
+#[derive(Envconfig)]
+struct Config {
+    /// logger level: valid values: "DEBUG", "INFO", "WARN", "ERROR"
+    #[envconfig(from = "LOGGING_LEVEL", default = "INFO")]
+    logging_level: String,
+    /// Folder with stored metrics. The server using it must be stopped
+    #[envconfig(from = "METRICS_ROOT", default = "metrics")]
+    metrics_root: String,
+    /// Number of shards the server will run with
+    #[envconfig(from = "SHARDS", default = "4")]
+    shards: usize,
+}
+
+fn main() {
+    let env_config = Config::init_from_env().unwrap();
+    println!("Setting logger level: {}", env_config.logging_level);
+    std::env::set_var("RUST_LOG", env_config.logging_level.clone());
+    env_logger::init();
+    let stats = reshard(&env_config.metrics_root, env_config.shards).unwrap();
+    info!(
+        "Moved {} samples from {} segments to {} segments",
+        stats.samples, stats.segments_read, stats.segments_written
+    );
+}
@@ -8,10 +8,13 @@ use chrono::Duration;
 use tp1::alarm::AlarmManager;
 use tp1::connection_handler::ConnectionHandler;
 use tp1::load_balancer::LoadBalancer;
+use tp1::metric::cache::QueryCache;
 use tp1::metric::metric_writer::MetricWriterPool;
 use tp1::metric::query_handler::QueryHandlerPool;
+use tp1::metric::router::ShardRouter;
 
 const METRIC_WRITER_POOL_SIZE: usize = 4;
+/// Evaluation interval of the alarms that don't configure one
 const ALARM_FREQUENCY_SECS: usize = 60;
 
 #[derive(Envconfig)]
@@ -28,6 +31,18 @@ struct Config {
     /// Folder with alarm configurations
     #[envconfig(from = "ALARM_FILE", default = "alarms.json")]
     alarm_file: String,
+    /// File with the notifiers of every alarm, events are printed if it doesn't exist
+    #[envconfig(from = "NOTIFIER_FILE", default = "notifiers.json")]
+    notifier_file: String,
+    /// File with the silences of the alarms, managed over the wire
+    #[envconfig(from = "SILENCE_FILE", default = "silences.json")]
+    silence_file: String,
+    /// Token clients must send to manage the alarms, which can't be managed over the wire if unset
+    #[envconfig(from = "ADMIN_TOKEN")]
+    admin_token: Option<String>,
+    /// Number of shards that wrote a metrics root without a manifest, which can't start without it
+    #[envconfig(from = "LEGACY_SHARDS")]
+    legacy_shards: Option<usize>,
 }
 
 fn main() {
@@ -45,6 +60,9 @@ fn run_server(config: Config) -> io::Result<()> {
     let term_flag = Arc::new(AtomicBool::new(false));
     signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&term_flag)).unwrap();
     signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&term_flag)).unwrap();
+    // SIGHUP reloads the alarm file
+    let reload_flag = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&reload_flag)).unwrap();
 
     let (connection_sender, connection_receiver) = channel();
 
@@ -70,14 +88,24 @@ fn run_server(config: Config) -> io::Result<()> {
     }
 
     let metrics_root = config.metrics_root;
+    let router = Arc::new(ShardRouter::open(&metrics_root, METRIC_WRITER_POOL_SIZE, config.legacy_shards)?);
+    let query_caches = (0..METRIC_WRITER_POOL_SIZE)
+        .map(|_| QueryCache::shared())
+        .collect::<Vec<_>>();
 
-    let mut metric_writer_pool = MetricWriterPool::new(metric_receivers, metrics_root.clone());
-    let mut query_handler_pool = QueryHandlerPool::new(query_receivers, metrics_root);
+    let mut metric_writer_pool = MetricWriterPool::new(metric_receivers, metrics_root.clone(), query_caches.clone());
+    let mut query_handler_pool = QueryHandlerPool::new(query_receivers, metrics_root.clone(), query_caches, router.clone());
 
-    let mut alarm_manager = AlarmManager::from_file(config.alarm_file, Duration::seconds(ALARM_FREQUENCY_SECS as i64))?;
-    alarm_manager.start(query_senders.clone(), term_flag);
+    let mut alarm_manager = AlarmManager::from_file(
+        config.alarm_file,
+        config.notifier_file,
+        config.silence_file,
+        Duration::seconds(ALARM_FREQUENCY_SECS as i64),
+    )?;
+    alarm_manager.start(router.clone(), query_senders.clone(), term_flag, reload_flag);
 
-    ConnectionHandler::run(connection_receiver, metric_senders, query_senders);
+    let alarm_admin = alarm_manager.admin(config.admin_token);
+    ConnectionHandler::run(connection_receiver, metric_senders, query_senders, router, metrics_root, alarm_admin);
 
     alarm_manager.stop();
     acceptor.stop();
@@ -1,21 +1,30 @@
-use crate::metric::{Metric, MetricAction, Query};
+use crate::alarm::admin::{AlarmAdmin, AlarmReply};
+use crate::metric::catalog::MetricIndex;
+use crate::metric::query::QueryParams;
+use crate::metric::query_handler::dispatch_query;
+use crate::metric::router::ShardRouter;
+use crate::metric::{parser, Metric, MetricAction, Query};
 use log::{debug, error, info, warn};
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
 use std::io;
 use std::io::Write;
 use std::net::TcpStream;
-use crossbeam_channel::{unbounded as channel, Receiver, Sender};
+use std::sync::Arc;
+use crossbeam_channel::{Receiver, Sender};
 use std::time::{Duration, Instant};
 use threadpool::ThreadPool;
 
 const CONNECTION_MAX_WAIT: Duration = Duration::from_millis(200);
+/// How long checking whether a client is gone waits for its socket to become readable
+const DISCONNECT_CHECK_WAIT: Duration = Duration::from_millis(1);
 const NUM_THREADS: usize = 4;
 
 pub struct ConnectionHandler {
     connection: TcpStream,
     metric_senders: Vec<Sender<Metric>>,
     query_senders: Vec<Sender<Query>>,
+    router: Arc<ShardRouter>,
+    metrics_root: String,
+    alarms: AlarmAdmin,
 }
 
 impl ConnectionHandler {
@@ -23,12 +32,18 @@ impl ConnectionHandler {
         connection_receiver: Receiver<TcpStream>,
         metric_senders: Vec<Sender<Metric>>,
         query_senders: Vec<Sender<Query>>,
+        router: Arc<ShardRouter>,
+        metrics_root: String,
+        alarms: AlarmAdmin,
     ) {
         info!("Starting pool with {:?} workers", NUM_THREADS);
         let pool = ThreadPool::new(NUM_THREADS);
         for connection in connection_receiver {
             let metric_senders_clone = metric_senders.clone();
             let query_senders_clone = query_senders.clone();
+            let router_clone = router.clone();
+            let root_clone = metrics_root.clone();
+            let alarms_clone = alarms.clone();
             let connection_ts = Instant::now();
             let job = move || {
                 if connection_ts.elapsed() < CONNECTION_MAX_WAIT {
@@ -37,6 +52,9 @@ impl ConnectionHandler {
                         connection,
                         metric_senders: metric_senders_clone,
                         query_senders: query_senders_clone,
+                        router: router_clone,
+                        metrics_root: root_clone,
+                        alarms: alarms_clone,
                     };
                     if let Err(e) = handler.handle_connection() {
                         error!("Failed to handle connection: {:?}", e);
@@ -60,28 +78,148 @@ impl ConnectionHandler {
     }
 
     pub fn send_action(&self, action: MetricAction, mut write_con: TcpStream) -> io::Result<()> {
-        let mut hasher = DefaultHasher::new();
         match action {
             MetricAction::Insert(metric) => {
-                metric.metric_id.hash(&mut hasher);
-                let hash = hasher.finish() as usize;
-                let idx = hash % self.metric_senders.len();
+                let idx = self.router.shard(&metric.metric_id);
                 debug!("Inserting {:?} into pipe {}", metric, idx);
                 self.metric_senders[idx].send(metric).ok();
                 write_con.write_all("OK".as_bytes())?;
             }
             MetricAction::Query(query_params) => {
-                query_params.metric_id.hash(&mut hasher);
-                let hash = hasher.finish() as usize;
-                let idx = hash % self.query_senders.len();
-                debug!("Querying {:?} in pipe {}", query_params, idx);
-                let (result_sender, result_recv) = channel();
-                let query = (query_params, result_sender);
-                self.query_senders[idx].send(query).ok();
-                let result = result_recv.recv().unwrap();
-                write_con.write_all(format!("{{ result: 'ok', value: {:?} }}\n", result).as_bytes())?;
+                self.stream_query(query_params, &mut write_con)?;
+            }
+            MetricAction::Expression(query) => {
+                debug!("Evaluating expression {:?}", query);
+                let abandoned = disconnect_check(&write_con)?;
+                match query.run(&self.router, &self.query_senders, abandoned) {
+                    Ok(result) => {
+                        let result = serde_json::to_string(&result)?;
+                        write_con.write_all(format!("{{ result: 'ok', value: {} }}\n", result).as_bytes())?
+                    }
+                    Err(e) => query_failed(&mut write_con, e)?,
+                }
+            }
+            MetricAction::Text(text) => match parser::parse(&text) {
+                Ok(action) => self.send_action(action, write_con)?,
+                Err(e) => {
+                    warn!("Invalid query {:?}: {}", text, e);
+                    write_error(&mut write_con, &e.to_string())?;
+                }
+            },
+            MetricAction::Catalog(query) => {
+                debug!("Listing metrics {:?}", query);
+                let abandoned = disconnect_check(&write_con)?;
+                let index = match MetricIndex::load_all(&self.metrics_root, abandoned) {
+                    Ok(index) => index,
+                    Err(e) => return query_failed(&mut write_con, e),
+                };
+                let page = index.page(&query);
+                let metrics = serde_json::to_string(&page.metrics)?;
+                let next = serde_json::to_string(&page.next)?;
+                write_con.write_all(format!("{{ result: 'ok', value: {}, next: {} }}\n", metrics, next).as_bytes())?;
+            }
+            MetricAction::Alarm { token, action } => {
+                debug!("Managing alarms {:?}", action);
+                match self.alarms.handle(&token, action) {
+                    Ok(AlarmReply::Done) => write_con.write_all("{ result: 'ok' }\n".as_bytes())?,
+                    Ok(listing) => {
+                        let listing = serde_json::to_string(&listing)?;
+                        write_con.write_all(format!("{{ result: 'ok', value: {} }}\n", listing).as_bytes())?
+                    }
+                    Err(e) => write_error(&mut write_con, &e.to_string())?,
+                }
             }
         }
         Ok(())
     }
+
+    /// Windowed queries are streamed, one line for each group of windows closed by every handler,
+    /// followed by a line with the totals. Other queries are answered in a single line. Queries
+    /// that time out with `partial` set are answered with the results so far, marked as partial
+    fn stream_query(&self, query_params: QueryParams, write_con: &mut TcpStream) -> io::Result<()> {
+        let abandoned = disconnect_check(write_con)?;
+        let mut stream = match dispatch_query(&self.router, &self.query_senders, &query_params) {
+            Ok(stream) => stream.cancel_when(abandoned),
+            Err(e) => return query_failed(write_con, e),
+        };
+        if query_params.window_length().is_none() {
+            return match stream.collect() {
+                Ok(partial) => {
+                    let result = query_params.finish(partial);
+                    let status = if stream.timed_out() { "partial" } else { "ok" };
+                    write_con.write_all(format!("{{ result: '{}', value: {} }}\n", status, result).as_bytes())
+                }
+                Err(e) => query_failed(write_con, e),
+            };
+        }
+        let mut values = 0;
+        while let Some(chunk) = stream.next_closed() {
+            match chunk {
+                Ok(partial) => {
+                    let result = query_params.finish(partial);
+                    values += result.len();
+                    write_con.write_all(format!("{{ result: 'chunk', value: {} }}\n", result).as_bytes())?;
+                }
+                Err(e) => return query_failed(write_con, e),
+            }
+        }
+        let status = if stream.timed_out() { "partial" } else { "ok" };
+        write_con.write_all(format!("{{ result: '{}', values: {} }}\n", status, values).as_bytes())
+    }
+}
+
+/// Writes the error of a query, unless it failed because the client is gone
+fn query_failed(write_con: &mut TcpStream, e: io::Error) -> io::Result<()> {
+    if e.kind() == io::ErrorKind::ConnectionAborted {
+        debug!("Client disconnected, query cancelled");
+        return Ok(());
+    }
+    write_error(write_con, &e.to_string())
+}
+
+/// Tells whether the client closed the connection. Clients send nothing after their action, so
+/// the socket only becomes readable when it is closed. The check peeks on its own handle with a
+/// short read timeout, which leaves writes to the connection blocking
+fn disconnect_check(write_con: &TcpStream) -> io::Result<impl Fn() -> bool + Clone + 'static> {
+    let connection = write_con.try_clone()?;
+    connection.set_read_timeout(Some(DISCONNECT_CHECK_WAIT))?;
+    let connection = Arc::new(connection);
+    Ok(move || match connection.peek(&mut [0]) {
+        Ok(read) => read == 0,
+        Err(e) => !matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut),
+    })
+}
+
+fn write_error(write_con: &mut TcpStream, message: &str) -> io::Result<()> {
+    write_con.write_all(format!("{{ result: 'error', message: {:?} }}\n", message).as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn disconnect_check_leaves_writes_blocking() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let abandoned = disconnect_check(&server).unwrap();
+        assert!(!abandoned());
+        // Writes bigger than the socket buffers only go through if they block
+        let reader = std::thread::spawn(move || {
+            let mut received = vec![];
+            client.read_to_end(&mut received).unwrap();
+            received.len()
+        });
+        server.write_all(&vec![b'x'; 8 * 1024 * 1024]).unwrap();
+        assert!(!abandoned());
+        server.shutdown(std::net::Shutdown::Write).unwrap();
+        assert_eq!(reader.join().unwrap(), 8 * 1024 * 1024);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !abandoned() {
+            assert!(Instant::now() < deadline, "the disconnection wasn't noticed");
+        }
+    }
 }
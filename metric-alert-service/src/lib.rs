@@ -2,3 +2,5 @@ pub mod alarm;
 pub mod connection_handler;
 pub mod load_balancer;
 pub mod metric;
+#[cfg(test)]
+pub(crate) mod test_util;
@@ -0,0 +1,329 @@
+use crate::metric::query::QueryAggregation;
+use std::collections::BTreeMap;
+
+/// Relative width of the histogram buckets, percentiles are within 1% of the exact value
+const BUCKET_GROWTH: f64 = 1.02;
+/// Offset of the bucket indexes of positive values, keeping them above the zero bucket
+const BUCKET_OFFSET: i32 = 10_000;
+/// Values closer to zero than this fall in the zero bucket
+const MIN_BUCKET_VALUE: f64 = 1e-9;
+
+/// Windows of a series, indexed by the timestamp where each window starts
+pub type Windows = BTreeMap<i64, AggregateState>;
+
+/// Partial aggregation of a set of values. States computed by different shards for the same
+/// window can be merged without losing information, which is not the case for the final values
+#[derive(Clone, Debug, PartialEq)]
+pub struct AggregateState {
+    pub count: u64,
+    pub sum: f64,
+    pub min: f32,
+    pub max: f32,
+    /// Only filled by percentile queries, segment footers don't keep it
+    pub histogram: Histogram,
+}
+
+/// Counts of values in buckets growing exponentially away from zero. Bucket keys are ordered as
+/// the values they hold, so histograms merge by adding the counts of their buckets
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histogram {
+    buckets: BTreeMap<i32, u64>,
+}
+
+impl Default for AggregateState {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            sum: 0.0,
+            min: f32::MAX,
+            max: f32::MIN,
+            histogram: Histogram::default(),
+        }
+    }
+}
+
+impl AggregateState {
+    pub fn push(&mut self, value: f32) {
+        self.count += 1;
+        self.sum += value as f64;
+        self.min = f32::min(self.min, value);
+        self.max = f32::max(self.max, value);
+    }
+
+    pub fn merge(&mut self, other: &AggregateState) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = f32::min(self.min, other.min);
+        self.max = f32::max(self.max, other.max);
+        self.histogram.merge(&other.histogram);
+    }
+
+    /// Final value of the aggregation, `None` if there is nothing to aggregate
+    pub fn value(&self, aggregation: &QueryAggregation) -> Option<f32> {
+        match aggregation {
+            QueryAggregation::Count => Some(self.count as f32),
+            _ if self.count == 0 => None,
+            QueryAggregation::Avg => Some((self.sum / self.count as f64) as f32),
+            QueryAggregation::Min => Some(self.min),
+            QueryAggregation::Max => Some(self.max),
+            // The extremes are known exactly
+            QueryAggregation::Percentile(percentile) if *percentile <= 0.0 => Some(self.min),
+            QueryAggregation::Percentile(percentile) if *percentile >= 100.0 => Some(self.max),
+            QueryAggregation::Percentile(percentile) => self
+                .histogram
+                .percentile(*percentile)
+                .map(|value| value.clamp(self.min, self.max)),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, value: f32) {
+        *self.buckets.entry(bucket(value)).or_default() += 1;
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (bucket, count) in &other.buckets {
+            *self.buckets.entry(*bucket).or_default() += count;
+        }
+    }
+
+    /// Value below which `percentile` percent of the values are, using the nearest rank.
+    /// `None` if the histogram is empty
+    pub fn percentile(&self, percentile: f32) -> Option<f32> {
+        let count = self.buckets.values().sum::<u64>();
+        let rank = ((percentile as f64 / 100.0) * count as f64).ceil().clamp(1.0, count as f64) as u64;
+        let mut seen = 0;
+        self.buckets
+            .iter()
+            .find(|(_, bucket_count)| {
+                seen += *bucket_count;
+                seen >= rank
+            })
+            .map(|(bucket, _)| bucket_value(*bucket))
+    }
+}
+
+/// Key of the bucket of a value. Bucket `i` above the offset holds the values in
+/// `(BUCKET_GROWTH^(i-1), BUCKET_GROWTH^i]`, negative values mirror positive ones
+fn bucket(value: f32) -> i32 {
+    let magnitude = (value as f64).abs();
+    if magnitude.is_nan() || magnitude < MIN_BUCKET_VALUE {
+        return 0;
+    }
+    let index = (magnitude.ln() / BUCKET_GROWTH.ln()).ceil() as i32 + BUCKET_OFFSET;
+    if value < 0.0 {
+        -index
+    } else {
+        index
+    }
+}
+
+/// Value in the middle of a bucket, relatively
+fn bucket_value(bucket: i32) -> f32 {
+    if bucket == 0 {
+        return 0.0;
+    }
+    let upper = BUCKET_GROWTH.powi(bucket.abs() - BUCKET_OFFSET);
+    let value = 2.0 * upper / (1.0 + BUCKET_GROWTH);
+    (value * bucket.signum() as f64) as f32
+}
+
+/// Partial result of a query, with the aggregation state of each window for every matched metric
+#[derive(Clone, Debug, Default)]
+pub struct PartialResult {
+    pub series: BTreeMap<String, Windows>,
+}
+
+impl PartialResult {
+    /// Adds a value to the window starting at `window_start` of the series `metric_id`, and to
+    /// its histogram if `histogram` is set
+    pub fn push(&mut self, metric_id: &str, window_start: i64, value: f32, histogram: bool) {
+        if !self.series.contains_key(metric_id) {
+            self.series.insert(metric_id.to_string(), Windows::new());
+        }
+        if let Some(windows) = self.series.get_mut(metric_id) {
+            let state = windows.entry(window_start).or_default();
+            state.push(value);
+            if histogram {
+                state.histogram.record(value);
+            }
+        }
+    }
+
+    /// Merges an aggregation state into the window starting at `window_start` of `metric_id`
+    pub fn push_state(&mut self, metric_id: &str, window_start: i64, state: &AggregateState) {
+        if !self.series.contains_key(metric_id) {
+            self.series.insert(metric_id.to_string(), Windows::new());
+        }
+        if let Some(windows) = self.series.get_mut(metric_id) {
+            windows.entry(window_start).or_default().merge(state);
+        }
+    }
+
+    /// Merges the result of another shard into this one
+    pub fn merge(&mut self, other: PartialResult) {
+        for (metric_id, other_windows) in other.series {
+            let windows = self.series.entry(metric_id).or_default();
+            merge_windows(windows, &other_windows);
+        }
+    }
+
+    /// Removes and returns the windows starting before `limit`
+    pub fn split_before(&mut self, limit: i64) -> PartialResult {
+        let mut before = PartialResult::default();
+        for (metric_id, windows) in self.series.iter_mut() {
+            let after = windows.split_off(&limit);
+            let windows = std::mem::replace(windows, after);
+            if !windows.is_empty() {
+                before.series.insert(metric_id.clone(), windows);
+            }
+        }
+        self.series.retain(|_, windows| !windows.is_empty());
+        before
+    }
+
+    /// Removes the windows starting in `[from, until)`
+    pub fn remove_range(&mut self, from: i64, until: i64) {
+        for windows in self.series.values_mut() {
+            let mut after = windows.split_off(&from).split_off(&until);
+            windows.append(&mut after);
+        }
+        self.series.retain(|_, windows| !windows.is_empty());
+    }
+
+    /// Merges all the series into a single one
+    pub fn combined(&self) -> Windows {
+        let mut combined = Windows::new();
+        for windows in self.series.values() {
+            merge_windows(&mut combined, windows);
+        }
+        combined
+    }
+}
+
+pub fn merge_windows(windows: &mut Windows, other: &Windows) {
+    for (window_start, state) in other {
+        windows.entry(*window_start).or_default().merge(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(values: &[f32], histogram: bool) -> AggregateState {
+        let mut state = AggregateState::default();
+        for value in values {
+            state.push(*value);
+            if histogram {
+                state.histogram.record(*value);
+            }
+        }
+        state
+    }
+
+    fn shard(samples: &[(&str, i64, f32)]) -> PartialResult {
+        let mut partial = PartialResult::default();
+        for (metric_id, window_start, value) in samples {
+            partial.push(metric_id, *window_start, *value, true);
+        }
+        partial
+    }
+
+    #[test]
+    fn merged_states_match_the_state_of_all_the_values() {
+        let mut merged = state(&[1.0, 5.0, -2.0], true);
+        merged.merge(&state(&[10.0, 0.5], true));
+        merged.merge(&AggregateState::default());
+        assert_eq!(merged, state(&[1.0, 5.0, -2.0, 10.0, 0.5], true));
+        assert_eq!(merged.value(&QueryAggregation::Count), Some(5.0));
+        assert_eq!(merged.value(&QueryAggregation::Avg), Some(2.9));
+        assert_eq!(merged.value(&QueryAggregation::Min), Some(-2.0));
+        assert_eq!(merged.value(&QueryAggregation::Max), Some(10.0));
+    }
+
+    #[test]
+    fn empty_states_only_have_a_count() {
+        let empty = AggregateState::default();
+        assert_eq!(empty.value(&QueryAggregation::Count), Some(0.0));
+        for aggregation in [
+            QueryAggregation::Avg,
+            QueryAggregation::Min,
+            QueryAggregation::Max,
+            QueryAggregation::Percentile(99.0),
+        ] {
+            assert_eq!(empty.value(&aggregation), None);
+        }
+    }
+
+    #[test]
+    fn percentiles_are_within_a_percent() {
+        let values = (1..=1000).map(|value| value as f32).collect::<Vec<_>>();
+        let state = state(&values, true);
+        for (percentile, exact) in [(50.0, 500.0), (90.0, 900.0), (99.0, 990.0), (99.9, 999.0)] {
+            let value = state.value(&QueryAggregation::Percentile(percentile)).unwrap();
+            assert!((value - exact).abs() <= exact * 0.01, "p{} is {}", percentile, value);
+        }
+        assert_eq!(state.value(&QueryAggregation::Percentile(0.0)), Some(1.0));
+        assert_eq!(state.value(&QueryAggregation::Percentile(100.0)), Some(1000.0));
+    }
+
+    #[test]
+    fn percentiles_order_negative_and_zero_values() {
+        let state = state(&[-100.0, -1.0, 0.0, 0.0, 1.0, 100.0], true);
+        let percentile = |percentile| state.value(&QueryAggregation::Percentile(percentile)).unwrap();
+        assert_eq!(percentile(0.0), -100.0);
+        assert!((percentile(20.0) + 1.0).abs() <= 0.01);
+        assert_eq!(percentile(50.0), 0.0);
+        assert!((percentile(80.0) - 1.0).abs() <= 0.01);
+    }
+
+    #[test]
+    fn percentiles_of_merged_shards_match_a_single_shard() {
+        let values = (0..500).map(|value| (value * 37 % 500) as f32 / 7.0).collect::<Vec<_>>();
+        let (left, right) = values.split_at(123);
+        let mut merged = state(left, true);
+        merged.merge(&state(right, true));
+        let single = state(&values, true);
+        for percentile in [1.0, 25.0, 50.0, 95.0, 99.0] {
+            let aggregation = QueryAggregation::Percentile(percentile);
+            assert_eq!(merged.value(&aggregation), single.value(&aggregation));
+        }
+    }
+
+    #[test]
+    fn partial_results_merge_by_series_and_window() {
+        let mut result = shard(&[("a", 0, 1.0), ("a", 10, 2.0), ("b", 0, 3.0)]);
+        result.merge(shard(&[("a", 10, 4.0), ("c", 20, 5.0)]));
+        assert_eq!(result.series.keys().collect::<Vec<_>>(), ["a", "b", "c"]);
+        assert_eq!(result.series["a"][&0], state(&[1.0], true));
+        assert_eq!(result.series["a"][&10], state(&[2.0, 4.0], true));
+        let combined = result.combined();
+        assert_eq!(combined.keys().collect::<Vec<_>>(), [&0, &10, &20]);
+        assert_eq!(combined[&0], state(&[1.0, 3.0], true));
+    }
+
+    #[test]
+    fn pushed_states_merge_into_windows() {
+        let mut result = shard(&[("a", 0, 1.0)]);
+        result.push_state("a", 0, &state(&[3.0], false));
+        result.push_state("b", 10, &state(&[2.0], false));
+        assert_eq!(result.series["a"][&0].value(&QueryAggregation::Avg), Some(2.0));
+        assert_eq!(result.series["b"][&10].value(&QueryAggregation::Count), Some(1.0));
+    }
+
+    #[test]
+    fn windows_split_and_are_removed_by_range() {
+        let mut result = shard(&[("a", 0, 1.0), ("a", 10, 2.0), ("a", 20, 3.0), ("b", 20, 4.0)]);
+        let before = result.split_before(10);
+        assert_eq!(before.series.keys().collect::<Vec<_>>(), ["a"]);
+        assert_eq!(before.series["a"].keys().collect::<Vec<_>>(), [&0]);
+        assert_eq!(result.series["a"].keys().collect::<Vec<_>>(), [&10, &20]);
+
+        result.remove_range(10, 20);
+        assert_eq!(result.series["a"].keys().collect::<Vec<_>>(), [&20]);
+        result.remove_range(0, 30);
+        assert!(result.series.is_empty());
+    }
+}
@@ -0,0 +1,92 @@
+use crate::metric::hash::{fnv1a, mix};
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Write};
+
+/// Bits used for each metric id, giving about 1% of false positives
+const BITS_PER_ID: usize = 10;
+const HASH_COUNT: u8 = 7;
+
+/// Set of metric ids that may give false positives but never false negatives, stored next to
+/// each segment to skip the segments without the queried metric
+#[derive(Clone, Debug)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hashes: u8,
+}
+
+impl BloomFilter {
+    pub fn with_capacity(ids: usize) -> Self {
+        let bytes = (ids * BITS_PER_ID).div_ceil(8).max(8);
+        Self {
+            bits: vec![0; bytes],
+            hashes: HASH_COUNT,
+        }
+    }
+
+    pub fn from_ids<'a>(ids: impl ExactSizeIterator<Item = &'a String>) -> Self {
+        let mut filter = Self::with_capacity(ids.len());
+        for id in ids {
+            filter.insert(id);
+        }
+        filter
+    }
+
+    pub fn insert(&mut self, id: &str) {
+        for bit in self.bit_indexes(id) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    pub fn may_contain(&self, id: &str) -> bool {
+        self.bit_indexes(id)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    fn bit_indexes(&self, id: &str) -> impl Iterator<Item = usize> {
+        // Filters are persisted, so they need a hash that doesn't change between builds. Every
+        // index is derived from two independent hashes
+        let first = fnv1a(id.as_bytes());
+        let second = mix(first) | 1;
+        let bit_count = (self.bits.len() * 8) as u64;
+        (0..self.hashes as u64)
+            .map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % bit_count) as usize)
+    }
+
+    /// Loads the filter of a segment, `None` if the segment has no filter
+    pub fn load(path: &str) -> io::Result<Option<Self>> {
+        match File::open(path) {
+            Ok(file) => Self::from_stream(&mut BufReader::new(file)).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        self.write_to(&mut file)
+    }
+
+    fn from_stream<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let mut hashes = [0];
+        stream.read_exact(&mut hashes)?;
+        let mut size_buf = [0; 4];
+        stream.read_exact(&mut size_buf)?;
+        let size = u32::from_be_bytes(size_buf) as usize;
+        if size == 0 || hashes[0] == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid bloom filter"));
+        }
+        let mut bits = vec![0; size];
+        stream.read_exact(&mut bits)?;
+        Ok(Self {
+            bits,
+            hashes: hashes[0],
+        })
+    }
+
+    fn write_to<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        stream.write_all(&[self.hashes])?;
+        stream.write_all(&(self.bits.len() as u32).to_be_bytes())?;
+        stream.write_all(&self.bits)
+    }
+}
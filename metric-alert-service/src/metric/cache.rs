@@ -0,0 +1,275 @@
+use crate::metric::aggregate::PartialResult;
+use crate::metric::query::{MetricSelector, QueryParams};
+use crate::metric::TEMP_FILE_LIFETIME;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Queries cached by each shard
+const QUERY_CACHE_CAPACITY: usize = 64;
+
+/// Cache of a shard, shared by its writer and its query handler
+pub type SharedQueryCache = Arc<Mutex<QueryCache>>;
+
+/// Queries selecting the same metrics with the same windows share their windows, whatever
+/// their aggregation and range, except windows without histograms can't answer percentiles
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct CacheKey {
+    metric_id: String,
+    selector: MetricSelector,
+    window_length: i64,
+    histogram: bool,
+}
+
+/// Windows starting in `[from, until)`, computed from every sample in them
+struct CacheEntry {
+    from: i64,
+    until: i64,
+    windows: PartialResult,
+    last_used: u64,
+}
+
+/// Windows cached for a query
+pub struct CachedWindows {
+    pub from: i64,
+    pub until: i64,
+    pub windows: PartialResult,
+}
+
+/// Least recently used cache with the closed windows of the windowed queries run on a shard.
+/// A window is closed once the segments covering it are published, as rotated segments are
+/// never written again
+#[derive(Default)]
+pub struct QueryCache {
+    entries: HashMap<CacheKey, CacheEntry>,
+    /// End of the newest published segment, later windows may still change
+    published_until: Option<i64>,
+    uses: u64,
+}
+
+impl CacheKey {
+    fn new(query: &QueryParams) -> Option<Self> {
+        Some(Self {
+            metric_id: query.metric_id.clone(),
+            selector: query.selector.clone(),
+            window_length: query.window_length()?,
+            histogram: query.aggregation.needs_histogram(),
+        })
+    }
+}
+
+impl QueryCache {
+    pub fn shared() -> SharedQueryCache {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    /// Cached windows of a query that are fully inside the range `[from, to]`, in seconds
+    pub fn get(&mut self, query: &QueryParams, (from, to): (i64, i64)) -> Option<CachedWindows> {
+        let key = CacheKey::new(query)?;
+        self.uses += 1;
+        let entry = self.entries.get_mut(&key)?;
+        entry.last_used = self.uses;
+        let from = entry.from.max(align_up(from, key.window_length));
+        let until = entry.until.min(align_down(to.saturating_add(1), key.window_length));
+        if from >= until {
+            return None;
+        }
+        let mut windows = entry.windows.clone();
+        windows.split_before(from);
+        let windows = windows.split_before(until);
+        Some(CachedWindows {
+            from,
+            until,
+            windows,
+        })
+    }
+
+    /// End of the newest published segment. Queries read it before listing the segments, as
+    /// segments published afterwards aren't part of their windows
+    pub fn published_until(&self) -> Option<i64> {
+        self.published_until
+    }
+
+    /// Caches the windows of a query run on the range `[from, to]`, over the segments published
+    /// until `published_until`. Only the windows fully inside the range and before the end of
+    /// those segments are kept
+    pub fn put(
+        &mut self,
+        query: &QueryParams,
+        (from, to): (i64, i64),
+        mut windows: PartialResult,
+        published_until: Option<i64>,
+    ) {
+        let (Some(key), Some(published_until)) = (CacheKey::new(query), published_until) else {
+            return;
+        };
+        let from = align_up(from, key.window_length);
+        let until = align_down(to.saturating_add(1), key.window_length)
+            .min(align_down(published_until, key.window_length));
+        if from >= until {
+            return;
+        }
+        windows.split_before(from);
+        let windows = windows.split_before(until);
+        if self.entries.len() >= QUERY_CACHE_CAPACITY && !self.entries.contains_key(&key) {
+            self.evict();
+        }
+        self.uses += 1;
+        let entry = CacheEntry {
+            from,
+            until,
+            windows,
+            last_used: self.uses,
+        };
+        self.entries.insert(key, entry);
+    }
+
+    /// Called by the writer when it publishes the segment starting at `segment_start`. Cached
+    /// windows that may hold samples of the new segment are dropped
+    pub fn publish(&mut self, segment_start: i64) {
+        let segment_end = segment_start + TEMP_FILE_LIFETIME;
+        self.published_until = Some(match self.published_until {
+            Some(until) => until.max(segment_end),
+            None => segment_end,
+        });
+        for (key, entry) in self.entries.iter_mut() {
+            if entry.until > segment_start {
+                entry.until = align_down(segment_start, key.window_length);
+                entry.windows = entry.windows.split_before(entry.until);
+            }
+        }
+        self.entries.retain(|_, entry| entry.from < entry.until);
+    }
+
+    fn evict(&mut self) {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone());
+        if let Some(key) = oldest {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+fn align_down(timestamp: i64, window_length: i64) -> i64 {
+    timestamp.div_euclid(window_length) * window_length
+}
+
+fn align_up(timestamp: i64, window_length: i64) -> i64 {
+    let aligned = align_down(timestamp, window_length);
+    if aligned == timestamp {
+        aligned
+    } else {
+        aligned + window_length
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metric::query::QueryAggregation;
+
+    fn query(metric_id: &str, aggregation: QueryAggregation) -> QueryParams {
+        QueryParams {
+            metric_id: metric_id.to_string(),
+            selector: MetricSelector::Exact,
+            combine: false,
+            date_range: None,
+            aggregation,
+            window_secs: 10.0,
+            ranking: None,
+            timeout_ms: None,
+            partial: false,
+        }
+    }
+
+    /// A value in every window starting in `[from, until)`
+    fn windows(from: i64, until: i64) -> PartialResult {
+        let mut windows = PartialResult::default();
+        for window_start in (from..until).step_by(10) {
+            windows.push("a", window_start, 1.0, true);
+        }
+        windows
+    }
+
+    fn cached_span(cache: &mut QueryCache, query: &QueryParams, range: (i64, i64)) -> Option<(i64, i64, usize)> {
+        cache
+            .get(query, range)
+            .map(|cached| (cached.from, cached.until, cached.windows.series["a"].len()))
+    }
+
+    #[test]
+    fn only_windows_of_published_segments_are_cached() {
+        let avg = query("a", QueryAggregation::Avg);
+        let mut cache = QueryCache::default();
+        cache.put(&avg, (0, 99), windows(0, 100), cache.published_until());
+        assert!(cache.get(&avg, (0, 99)).is_none());
+
+        cache.publish(45);
+        assert_eq!(cache.published_until(), Some(50));
+        // The last window isn't fully inside the range, and the ones after 50 may still change
+        cache.put(&avg, (3, 99), windows(0, 100), cache.published_until());
+        assert_eq!(cached_span(&mut cache, &avg, (0, 99)), Some((10, 50, 4)));
+        // No cached window is fully inside the range
+        assert!(cache.get(&avg, (25, 35)).is_none());
+        assert_eq!(cached_span(&mut cache, &avg, (20, 39)), Some((20, 40, 2)));
+    }
+
+    #[test]
+    fn windows_of_segments_published_during_the_query_are_not_cached() {
+        let avg = query("a", QueryAggregation::Avg);
+        let mut cache = QueryCache::default();
+        cache.publish(10);
+        let published_until = cache.published_until();
+        // Published after the query listed its segments, so its samples aren't in the windows
+        cache.publish(20);
+        cache.put(&avg, (0, 99), windows(0, 100), published_until);
+        assert_eq!(cached_span(&mut cache, &avg, (0, 99)), Some((0, 10, 1)));
+    }
+
+    #[test]
+    fn publishing_drops_the_windows_of_the_new_segment() {
+        let avg = query("a", QueryAggregation::Avg);
+        let mut cache = QueryCache::default();
+        cache.publish(95);
+        cache.put(&avg, (0, 99), windows(0, 100), cache.published_until());
+        assert_eq!(cached_span(&mut cache, &avg, (0, 99)), Some((0, 100, 10)));
+        // A segment may hold samples older than the newest cached window
+        cache.publish(42);
+        assert_eq!(cached_span(&mut cache, &avg, (0, 99)), Some((0, 40, 4)));
+        cache.publish(0);
+        assert!(cache.get(&avg, (0, 99)).is_none());
+    }
+
+    #[test]
+    fn aggregations_share_windows_unless_they_need_histograms() {
+        let mut cache = QueryCache::default();
+        cache.publish(95);
+        let range = (0, 99);
+        cache.put(&query("a", QueryAggregation::Avg), range, windows(0, 100), cache.published_until());
+        assert!(cache.get(&query("a", QueryAggregation::Max), range).is_some());
+        assert!(cache.get(&query("a", QueryAggregation::Percentile(99.0)), range).is_none());
+        assert!(cache.get(&query("b", QueryAggregation::Avg), range).is_none());
+        let mut single_window = query("a", QueryAggregation::Avg);
+        single_window.window_secs = 0.0;
+        assert!(cache.get(&single_window, range).is_none());
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_query() {
+        let mut cache = QueryCache::default();
+        cache.publish(95);
+        let queries = (0..=QUERY_CACHE_CAPACITY)
+            .map(|idx| query(&format!("m{}", idx), QueryAggregation::Avg))
+            .collect::<Vec<_>>();
+        for query in &queries[..QUERY_CACHE_CAPACITY] {
+            cache.put(query, (0, 99), windows(0, 100), cache.published_until());
+        }
+        cache.get(&queries[0], (0, 99));
+        cache.put(&queries[QUERY_CACHE_CAPACITY], (0, 99), windows(0, 100), cache.published_until());
+        assert_eq!(cache.entries.len(), QUERY_CACHE_CAPACITY);
+        assert!(cache.entries.contains_key(&CacheKey::new(&queries[0]).unwrap()));
+        assert!(!cache.entries.contains_key(&CacheKey::new(&queries[1]).unwrap()));
+    }
+}
@@ -0,0 +1,278 @@
+use crate::metric::{read_string, write_string};
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::ops::Bound;
+
+const INDEX_FOLDER: &str = "index";
+const DEFAULT_LIMIT: u32 = 100;
+
+/// Summary of the stored samples of a metric
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MetricStats {
+    pub metric_id: String,
+    pub first: DateTime<Utc>,
+    pub last: DateTime<Utc>,
+    pub count: u64,
+}
+
+/// Stats of every metric stored in rotated segments, indexed by metric id
+#[derive(Clone, Debug, Default)]
+pub struct MetricIndex {
+    metrics: BTreeMap<String, MetricStats>,
+}
+
+/// Lists the stored metrics, sorted by id. Pages are requested by passing the last id of the
+/// previous page in `after`
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CatalogQuery {
+    #[serde(default)]
+    pub prefix: Option<String>,
+    #[serde(default)]
+    pub after: Option<String>,
+    #[serde(default)]
+    pub limit: Option<u32>,
+}
+
+/// A page of the catalog, with the cursor for the next one if there are more metrics
+#[derive(Debug, Serialize)]
+pub struct CatalogPage {
+    pub metrics: Vec<MetricStats>,
+    pub next: Option<String>,
+}
+
+impl MetricStats {
+    fn merge(&mut self, other: &MetricStats) {
+        self.first = self.first.min(other.first);
+        self.last = self.last.max(other.last);
+        self.count += other.count;
+    }
+}
+
+impl MetricIndex {
+    /// Loads the index of a single writer, empty if it doesn't exist yet
+    pub fn load(metrics_root: &str, writer_id: usize) -> io::Result<Self> {
+        match File::open(index_path(metrics_root, writer_id)) {
+            Ok(file) => Self::from_reader(file),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Loads and merges the indexes of every writer, giving up as soon as `abandoned` returns true
+    pub fn load_all(metrics_root: &str, abandoned: impl Fn() -> bool) -> io::Result<Self> {
+        let mut index = Self::default();
+        let folder = format!("{}/{}", metrics_root, INDEX_FOLDER);
+        let paths = match std::fs::read_dir(folder) {
+            Ok(paths) => paths,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(index),
+            Err(e) => return Err(e),
+        };
+        for path in paths {
+            if abandoned() {
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "listing abandoned"));
+            }
+            let path = path?;
+            if path.file_name().to_string_lossy().ends_with(".index") {
+                index.merge(&Self::from_reader(File::open(path.path())?)?);
+            }
+        }
+        Ok(index)
+    }
+
+    fn from_reader<R: Read>(reader: R) -> io::Result<Self> {
+        let mut index = Self::default();
+        for line in BufReader::new(reader).lines() {
+            match serde_json::from_str::<MetricStats>(&line?) {
+                Ok(stats) => index.add(stats),
+                Err(e) => warn!("Skipping invalid index entry: {}", e),
+            }
+        }
+        Ok(index)
+    }
+
+    /// Writes the index of a writer. The file is replaced atomically, so readers never see a
+    /// partially written index
+    pub fn save(&self, metrics_root: &str, writer_id: usize) -> io::Result<()> {
+        std::fs::create_dir_all(format!("{}/{}", metrics_root, INDEX_FOLDER))?;
+        let path = index_path(metrics_root, writer_id);
+        let tmp_path = format!("{}.tmp", path);
+        let mut file = File::create(&tmp_path)?;
+        for stats in self.metrics.values() {
+            serde_json::to_writer(&mut file, stats)?;
+            file.write_all(b"\n")?;
+        }
+        file.sync_all()?;
+        std::fs::rename(tmp_path, path)
+    }
+
+    /// Records a sample written at `timestamp`
+    pub fn record(&mut self, metric_id: &str, timestamp: DateTime<Utc>) {
+        match self.metrics.get_mut(metric_id) {
+            Some(stats) => {
+                stats.first = stats.first.min(timestamp);
+                stats.last = stats.last.max(timestamp);
+                stats.count += 1;
+            }
+            None => self.add(MetricStats {
+                metric_id: metric_id.to_string(),
+                first: timestamp,
+                last: timestamp,
+                count: 1,
+            }),
+        }
+    }
+
+    pub fn add(&mut self, stats: MetricStats) {
+        match self.metrics.get_mut(&stats.metric_id) {
+            Some(current) => current.merge(&stats),
+            None => {
+                self.metrics.insert(stats.metric_id.clone(), stats);
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &MetricIndex) {
+        for stats in other.metrics.values() {
+            self.add(stats.clone());
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.metrics.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.metrics.clear();
+    }
+
+    pub fn page(&self, query: &CatalogQuery) -> CatalogPage {
+        let prefix = query.prefix.as_deref().unwrap_or("");
+        let limit = query.limit.unwrap_or(DEFAULT_LIMIT) as usize;
+        // Ids sharing the prefix are contiguous, starting at the prefix itself
+        let start = match &query.after {
+            Some(after) if after.as_str() >= prefix => Bound::Excluded(after.clone()),
+            _ => Bound::Included(prefix.to_string()),
+        };
+        let mut matching = self
+            .metrics
+            .range((start, Bound::Unbounded))
+            .map(|(_, stats)| stats)
+            .take_while(|stats| stats.metric_id.starts_with(prefix));
+        let metrics = matching.by_ref().take(limit).cloned().collect::<Vec<_>>();
+        let next = match matching.next() {
+            Some(_) => metrics.last().map(|stats| stats.metric_id.clone()),
+            None => None,
+        };
+        CatalogPage { metrics, next }
+    }
+}
+
+impl CatalogQuery {
+    pub fn from_stream<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let prefix = read_string(stream)?;
+        let after = read_string(stream)?;
+        let mut limit_buf = [0; 4];
+        stream.read_exact(&mut limit_buf)?;
+        let limit = u32::from_be_bytes(limit_buf);
+        Ok(Self {
+            prefix: Some(prefix).filter(|prefix| !prefix.is_empty()),
+            after: Some(after).filter(|after| !after.is_empty()),
+            limit: Some(limit).filter(|limit| *limit > 0),
+        })
+    }
+
+    pub fn write_to<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        write_string(stream, self.prefix.as_deref().unwrap_or(""))?;
+        write_string(stream, self.after.as_deref().unwrap_or(""))?;
+        stream.write_all(&self.limit.unwrap_or(0).to_be_bytes())?;
+        Ok(())
+    }
+}
+
+fn index_path(metrics_root: &str, writer_id: usize) -> String {
+    format!("{}/{}/{}.index", metrics_root, INDEX_FOLDER, writer_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use chrono::TimeZone;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp(1_650_000_000 + secs, 0)
+    }
+
+    fn index(samples: &[(&str, i64)]) -> MetricIndex {
+        let mut index = MetricIndex::default();
+        for (metric_id, secs) in samples {
+            index.record(metric_id, at(*secs));
+        }
+        index
+    }
+
+    fn ids(page: &CatalogPage) -> Vec<&str> {
+        page.metrics.iter().map(|stats| stats.metric_id.as_str()).collect()
+    }
+
+    #[test]
+    fn merged_indexes_keep_the_bounds_and_counts() {
+        let mut merged = index(&[("a", 10), ("a", 20), ("b", 5)]);
+        merged.merge(&index(&[("a", 0), ("a", 15), ("c", 1)]));
+        let page = merged.page(&CatalogQuery::default());
+        assert_eq!(ids(&page), ["a", "b", "c"]);
+        let a = &page.metrics[0];
+        assert_eq!((a.first, a.last, a.count), (at(0), at(20), 4));
+    }
+
+    #[test]
+    fn pages_follow_the_prefix_and_cursor() {
+        let index = index(&[("api.a", 0), ("api.b", 0), ("api.c", 0), ("apiary", 0), ("db.a", 0), ("ap", 0)]);
+        let query = |after: Option<&str>| CatalogQuery {
+            prefix: Some("api.".to_string()),
+            after: after.map(str::to_string),
+            limit: Some(2),
+        };
+        let first = index.page(&query(None));
+        assert_eq!(ids(&first), ["api.a", "api.b"]);
+        assert_eq!(first.next.as_deref(), Some("api.b"));
+        let second = index.page(&query(first.next.as_deref()));
+        assert_eq!(ids(&second), ["api.c"]);
+        assert_eq!(second.next, None);
+        // A cursor before the prefix starts at the prefix
+        assert_eq!(ids(&index.page(&query(Some("a")))), ["api.a", "api.b"]);
+    }
+
+    #[test]
+    fn saved_indexes_of_every_writer_are_loaded_together() {
+        let dir = TempDir::new("catalog-indexes");
+        let root = dir.path();
+        assert!(MetricIndex::load_all(root, || false).unwrap().is_empty());
+        index(&[("a", 0), ("b", 10)]).save(root, 0).unwrap();
+        index(&[("a", 30)]).save(root, 1).unwrap();
+        let loaded = MetricIndex::load_all(root, || false).unwrap();
+        let abandoned = MetricIndex::load_all(root, || true);
+        assert_eq!(abandoned.unwrap_err().kind(), io::ErrorKind::ConnectionAborted);
+        let page = loaded.page(&CatalogQuery::default());
+        assert_eq!(ids(&page), ["a", "b"]);
+        assert_eq!((page.metrics[0].first, page.metrics[0].last, page.metrics[0].count), (at(0), at(30), 2));
+    }
+
+    #[test]
+    fn queries_round_trip_on_the_wire() {
+        let query = CatalogQuery {
+            prefix: Some("api.".to_string()),
+            after: None,
+            limit: Some(5),
+        };
+        let mut bytes = vec![];
+        query.write_to(&mut bytes).unwrap();
+        let read = CatalogQuery::from_stream(&mut bytes.as_slice()).unwrap();
+        assert_eq!((read.prefix, read.after, read.limit), (query.prefix, query.after, query.limit));
+    }
+}
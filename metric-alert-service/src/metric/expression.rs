@@ -0,0 +1,359 @@
+use crate::metric::query::{MetricSelector, QueryAggregation, QueryParams};
+use crate::metric::query_handler::dispatch_query;
+use crate::metric::router::ShardRouter;
+use crate::metric::time::{from_timestamp, read_time_range, write_time_range, TimeRange};
+use crate::metric::{read_string, write_string, Query};
+use chrono::{DateTime, Utc};
+use crossbeam_channel::Sender;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::io::{Read, Write};
+
+/// Limits the nesting of expressions read from the network
+pub(crate) const MAX_EXPRESSION_DEPTH: usize = 32;
+/// Limits the metrics and constants of an expression, and so the queries it sends
+pub(crate) const MAX_EXPRESSION_OPERANDS: usize = 64;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub enum Operator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+/// Arithmetic expression over aggregated metrics, like `errors / requests * 100`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Expression {
+    Metric {
+        metric_id: String,
+        aggregation: QueryAggregation,
+    },
+    Constant(f32),
+    Binary(Box<Expression>, Operator, Box<Expression>),
+}
+
+/// Query evaluating an expression on each window of a date range
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ExpressionQuery {
+    pub expression: Expression,
+    #[serde(default)]
+    pub date_range: Option<TimeRange>,
+    pub window_secs: f32,
+    /// Time in milliseconds the server may spend on the operands before giving up
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+impl Operator {
+    fn apply(&self, left: f32, right: f32) -> f32 {
+        match self {
+            Operator::Add => left + right,
+            Operator::Sub => left - right,
+            Operator::Mul => left * right,
+            Operator::Div => left / right,
+        }
+    }
+}
+
+impl Expression {
+    /// Reads an expression nested `depth` levels deep, after `operands` other operands
+    pub fn from_stream<R: Read>(stream: &mut R, depth: usize, operands: &mut usize) -> io::Result<Self> {
+        if depth > MAX_EXPRESSION_DEPTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Expression too deep",
+            ));
+        }
+        let mut code = [0];
+        stream.read_exact(&mut code)?;
+        if code[0] != b'b' {
+            *operands += 1;
+            if *operands > MAX_EXPRESSION_OPERANDS {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Too many operands"));
+            }
+        }
+        match code[0] {
+            b'm' => {
+                let metric_id = read_string(stream)?;
+                let aggregation = QueryAggregation::from_stream(stream)?;
+                Ok(Expression::Metric {
+                    metric_id,
+                    aggregation,
+                })
+            }
+            b'k' => {
+                let mut value_buf = [0; 4];
+                stream.read_exact(&mut value_buf)?;
+                Ok(Expression::Constant(f32::from_be_bytes(value_buf)))
+            }
+            b'b' => {
+                stream.read_exact(&mut code)?;
+                let operator = match code[0] {
+                    b'+' => Operator::Add,
+                    b'-' => Operator::Sub,
+                    b'*' => Operator::Mul,
+                    b'/' => Operator::Div,
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid operator")),
+                };
+                let left = Expression::from_stream(stream, depth + 1, operands)?;
+                let right = Expression::from_stream(stream, depth + 1, operands)?;
+                Ok(Expression::Binary(Box::new(left), operator, Box::new(right)))
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid code")),
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        match self {
+            Expression::Metric {
+                metric_id,
+                aggregation,
+            } => {
+                stream.write_all(b"m")?;
+                write_string(stream, metric_id)?;
+                aggregation.write_to(stream)?;
+            }
+            Expression::Constant(value) => {
+                stream.write_all(b"k")?;
+                stream.write_all(&value.to_be_bytes())?;
+            }
+            Expression::Binary(left, operator, right) => {
+                let operator_code = match operator {
+                    Operator::Add => b'+',
+                    Operator::Sub => b'-',
+                    Operator::Mul => b'*',
+                    Operator::Div => b'/',
+                };
+                stream.write_all(&[b'b', operator_code])?;
+                left.write_to(stream)?;
+                right.write_to(stream)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of metrics and constants
+    fn len(&self) -> usize {
+        match self {
+            Expression::Metric { .. } | Expression::Constant(_) => 1,
+            Expression::Binary(left, _, right) => left.len() + right.len(),
+        }
+    }
+
+    /// Distinct metric operands, each one queried once however many times it is used
+    fn operands<'a>(&'a self, operands: &mut Vec<(&'a String, &'a QueryAggregation)>) {
+        match self {
+            Expression::Metric {
+                metric_id,
+                aggregation,
+            } if !operands.contains(&(metric_id, aggregation)) => operands.push((metric_id, aggregation)),
+            Expression::Metric { .. } | Expression::Constant(_) => {}
+            Expression::Binary(left, _, right) => {
+                left.operands(operands);
+                right.operands(operands);
+            }
+        }
+    }
+
+    /// Evaluates the expression for a single window, where `values` has the values of each of
+    /// `operands`. `None` if an operand has no value in it
+    fn evaluate(
+        &self,
+        window_start: i64,
+        operands: &[(&String, &QueryAggregation)],
+        values: &[BTreeMap<i64, f32>],
+    ) -> Option<f32> {
+        match self {
+            Expression::Metric {
+                metric_id,
+                aggregation,
+            } => {
+                let idx = operands.iter().position(|operand| *operand == (metric_id, aggregation))?;
+                values[idx].get(&window_start).copied()
+            }
+            Expression::Constant(value) => Some(*value),
+            Expression::Binary(left, operator, right) => Some(operator.apply(
+                left.evaluate(window_start, operands, values)?,
+                right.evaluate(window_start, operands, values)?,
+            )),
+        }
+    }
+}
+
+impl ExpressionQuery {
+    pub fn from_stream<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let expression = Expression::from_stream(stream, 0, &mut 0)?;
+        let date_range = read_time_range(stream)?;
+        let mut window_buf = [0; 4];
+        stream.read_exact(&mut window_buf)?;
+        let window_secs = f32::from_be_bytes(window_buf);
+        let mut timeout_buf = [0; 8];
+        stream.read_exact(&mut timeout_buf)?;
+        let timeout_ms = Some(u64::from_be_bytes(timeout_buf)).filter(|timeout| *timeout > 0);
+        Ok(Self {
+            expression,
+            date_range,
+            window_secs,
+            timeout_ms,
+        })
+    }
+
+    pub fn write_to<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        self.expression.write_to(stream)?;
+        write_time_range(stream, &self.date_range)?;
+        stream.write_all(&self.window_secs.to_be_bytes())?;
+        stream.write_all(&self.timeout_ms.unwrap_or(0).to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Queries every operand in its own handler and evaluates the expression on the windows
+    /// where any operand has values. Windows where an operand has no value, or where the result
+    /// is not finite, have no value. The operands share the timeout, and are cancelled as soon
+    /// as `abandoned` returns true
+    pub fn run(
+        &self,
+        router: &ShardRouter,
+        query_senders: &[Sender<Query>],
+        abandoned: impl Fn() -> bool + Clone + 'static,
+    ) -> io::Result<Vec<(DateTime<Utc>, Option<f32>)>> {
+        if self.expression.len() > MAX_EXPRESSION_OPERANDS {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Too many operands"));
+        }
+        let date_range = self.date_range.map(|range| range.resolved(Utc::now())).transpose()?;
+        let mut operands = vec![];
+        self.expression.operands(&mut operands);
+        let operand_queries = operands
+            .iter()
+            .map(|&(metric_id, aggregation)| QueryParams {
+                metric_id: metric_id.clone(),
+                selector: MetricSelector::Exact,
+                combine: false,
+                date_range,
+                aggregation: aggregation.clone(),
+                window_secs: self.window_secs,
+                ranking: None,
+                timeout_ms: self.timeout_ms,
+                partial: false,
+            })
+            .collect::<Vec<_>>();
+        let streams = operand_queries
+            .iter()
+            .map(|query_params| {
+                dispatch_query(router, query_senders, query_params)
+                    .map(|stream| stream.cancel_when(abandoned.clone()))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut values = Vec::with_capacity(streams.len());
+        for (mut stream, query_params) in streams.into_iter().zip(&operand_queries) {
+            let operand_values = stream
+                .collect()?
+                .combined()
+                .iter()
+                .flat_map(|(window_start, state)| {
+                    state
+                        .value(&query_params.aggregation)
+                        .map(|value| (*window_start, value))
+                })
+                .collect::<BTreeMap<_, _>>();
+            values.push(operand_values);
+        }
+        let windows = values
+            .iter()
+            .flat_map(|operand| operand.keys())
+            .collect::<BTreeSet<_>>();
+        windows
+            .into_iter()
+            .map(|window_start| {
+                let value = self.expression.evaluate(*window_start, &operands, &values);
+                Ok((from_timestamp(*window_start)?, value.filter(|value| value.is_finite())))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metric::time::TimePoint;
+
+    fn metric(metric_id: &str) -> Box<Expression> {
+        Box::new(Expression::Metric {
+            metric_id: metric_id.to_string(),
+            aggregation: QueryAggregation::Avg,
+        })
+    }
+
+    #[test]
+    fn queries_round_trip_on_the_wire() {
+        let query = ExpressionQuery {
+            expression: Expression::Binary(metric("errors"), Operator::Div, metric("requests")),
+            date_range: Some(TimeRange(TimePoint::Relative(-300), TimePoint::Relative(0))),
+            window_secs: 60.0,
+            timeout_ms: Some(1500),
+        };
+        let mut bytes = vec![];
+        query.write_to(&mut bytes).unwrap();
+        let read = ExpressionQuery::from_stream(&mut bytes.as_slice()).unwrap();
+        let mut operands = vec![];
+        read.expression.operands(&mut operands);
+        assert_eq!(operands.iter().map(|(metric_id, _)| metric_id.as_str()).collect::<Vec<_>>(), ["errors", "requests"]);
+        assert_eq!((read.date_range, read.window_secs, read.timeout_ms), (query.date_range, 60.0, Some(1500)));
+    }
+
+    #[test]
+    fn windows_missing_an_operand_have_no_value() {
+        let expression = Expression::Binary(
+            metric("a"),
+            Operator::Sub,
+            Box::new(Expression::Binary(metric("b"), Operator::Mul, Box::new(Expression::Constant(2.0)))),
+        );
+        let mut operands = vec![];
+        expression.operands(&mut operands);
+        let values = [BTreeMap::from([(0, 10.0), (60, 5.0)]), BTreeMap::from([(0, 1.0)])];
+        assert_eq!(expression.evaluate(0, &operands, &values), Some(8.0));
+        assert_eq!(expression.evaluate(60, &operands, &values), None);
+    }
+
+    #[test]
+    fn repeated_operands_are_queried_once() {
+        let rate = Expression::Binary(metric("errors"), Operator::Div, metric("requests"));
+        let expression = Expression::Binary(Box::new(rate.clone()), Operator::Mul, Box::new(rate));
+        let mut operands = vec![];
+        expression.operands(&mut operands);
+        assert_eq!(operands.iter().map(|(metric_id, _)| metric_id.as_str()).collect::<Vec<_>>(), ["errors", "requests"]);
+        let values = [BTreeMap::from([(0, 1.0)]), BTreeMap::from([(0, 4.0)])];
+        assert_eq!(expression.evaluate(0, &operands, &values), Some(0.0625));
+    }
+
+    #[test]
+    fn rejects_deep_expressions_from_the_wire() {
+        let mut expression = *metric("a");
+        for _ in 0..=MAX_EXPRESSION_DEPTH {
+            expression = Expression::Binary(Box::new(expression), Operator::Add, metric("a"));
+        }
+        let mut bytes = vec![];
+        expression.write_to(&mut bytes).unwrap();
+        assert!(Expression::from_stream(&mut bytes.as_slice(), 0, &mut 0).is_err());
+    }
+
+    #[test]
+    fn rejects_wide_expressions_from_the_wire() {
+        // Balanced, so well within the depth limit
+        let balanced = |leaves: usize| {
+            let mut level = vec![*metric("a"); leaves];
+            while level.len() > 1 {
+                level = level
+                    .chunks(2)
+                    .map(|pair| Expression::Binary(Box::new(pair[0].clone()), Operator::Add, Box::new(pair[1].clone())))
+                    .collect();
+            }
+            let mut bytes = vec![];
+            level[0].write_to(&mut bytes).unwrap();
+            Expression::from_stream(&mut bytes.as_slice(), 0, &mut 0)
+        };
+        assert_eq!(balanced(MAX_EXPRESSION_OPERANDS).unwrap().len(), MAX_EXPRESSION_OPERANDS);
+        assert!(balanced(MAX_EXPRESSION_OPERANDS * 2).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Hash functions assigning metrics to shards. Stored segments depend on them, so a variant must
+/// always hash the same way: new functions get new variants
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum HashFunction {
+    /// `DefaultHasher` of the standard library, which may change between Rust releases. Only
+    /// used to find data written before hashes were versioned
+    Legacy,
+    /// 64 bit FNV-1a of the UTF-8 bytes of the key. Keys differing in their last bytes get
+    /// close hashes, which spreads them badly among the points of a ring
+    Fnv1a,
+    /// `Fnv1a` followed by a 64 bit finalizer, so every bit of the key changes the whole hash
+    MixedFnv1a,
+}
+
+impl HashFunction {
+    /// Function used for new layouts
+    pub const CURRENT: HashFunction = HashFunction::MixedFnv1a;
+
+    pub fn hash(&self, key: &str) -> u64 {
+        match self {
+            HashFunction::Legacy => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                hasher.finish()
+            }
+            HashFunction::Fnv1a => fnv1a(key.as_bytes()),
+            HashFunction::MixedFnv1a => mix(fnv1a(key.as_bytes())),
+        }
+    }
+}
+
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Finalizer of MurmurHash3, spreading the changes of any input bit to every output bit
+pub(crate) fn mix(mut hash: u64) -> u64 {
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metric::router::{Placement, ShardLayout, ShardRouter};
+
+    #[test]
+    fn hashes_never_change() {
+        assert_eq!(HashFunction::Fnv1a.hash(""), 0xcbf29ce484222325);
+        assert_eq!(HashFunction::Fnv1a.hash("a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(HashFunction::Fnv1a.hash("foobar"), 0x85944171f73967e8);
+        assert_eq!(HashFunction::MixedFnv1a.hash(""), 0xefd01f60ba992926);
+        assert_eq!(HashFunction::MixedFnv1a.hash("metric_1"), 0x3c90a8bb4b4eeb4c);
+    }
+
+    #[test]
+    fn new_layouts_spread_similar_ids_evenly() {
+        let router = ShardRouter::new(vec![ShardLayout::current(8)]);
+        assert_eq!(ShardLayout::current(8).hash, HashFunction::MixedFnv1a);
+        let mut counts = [0; 8];
+        for idx in 0..10_000 {
+            counts[router.shard(&format!("metric_{}", idx))] += 1;
+        }
+        assert!(counts.iter().all(|count| (625..=1875).contains(count)), "{:?}", counts);
+        // Without the finalizer some shards get more than twice their share
+        let unmixed = ShardRouter::new(vec![ShardLayout {
+            placement: Placement::Ring(8),
+            hash: HashFunction::Fnv1a,
+        }]);
+        let mut counts = [0; 8];
+        for idx in 0..10_000 {
+            counts[unmixed.shard(&format!("metric_{}", idx))] += 1;
+        }
+        assert!(counts.iter().any(|count| *count > 2500), "{:?}", counts);
+    }
+}
@@ -1,4 +1,8 @@
-use crate::metric::Metric;
+use crate::metric::cache::SharedQueryCache;
+use crate::metric::catalog::MetricIndex;
+use crate::metric::bloom::BloomFilter;
+use crate::metric::segment::{bloom_path, SegmentSummary};
+use crate::metric::{Metric, TEMP_FILE_LIFETIME};
 use chrono::{DateTime, Duration, DurationRound, Utc};
 use log::{debug, warn};
 use std::fs::File;
@@ -6,19 +10,18 @@ use std::io;
 use crossbeam_channel::{Receiver, RecvTimeoutError};
 use threadpool::ThreadPool;
 
-const TEMP_FILE_LIFETIME: i64 = 5;
-
 pub struct MetricWriterPool {
     pool: ThreadPool,
 }
 
 impl MetricWriterPool {
-    pub fn new(receivers: Vec<Receiver<Metric>>, metrics_root: String) -> Self {
+    /// Each writer gets the query cache of its shard, `caches` has one for each receiver
+    pub fn new(receivers: Vec<Receiver<Metric>>, metrics_root: String, caches: Vec<SharedQueryCache>) -> Self {
         let pool = ThreadPool::new(receivers.len());
-        for (id, receiver) in receivers.into_iter().enumerate() {
+        for (id, (receiver, cache)) in receivers.into_iter().zip(caches).enumerate() {
             let root_clone = metrics_root.clone();
             pool.execute(move || {
-                let mut handler = MetricWriter::new(id, root_clone).unwrap();
+                let mut handler = MetricWriter::new(id, root_clone, cache).unwrap();
                 handler.run(receiver).unwrap();
             });
         }
@@ -35,32 +38,46 @@ struct MetricWriter {
     metrics_root: String,
     current_time_slice: DateTime<Utc>,
     current_file: File,
+    /// Metrics of the rotated segments
+    index: MetricIndex,
+    /// Metrics of the segment being written, added to `index` on rotation
+    current_index: MetricIndex,
+    /// Footer of the segment being written
+    current_summary: SegmentSummary,
+    /// Told about every published segment, so cached windows stay valid
+    cache: SharedQueryCache,
 }
 
 impl MetricWriter {
-    pub fn new(id: usize, metrics_root: String) -> io::Result<Self> {
+    pub fn new(id: usize, metrics_root: String, cache: SharedQueryCache) -> io::Result<Self> {
         let time = chrono::Utc::now();
         let trunc_time = time
             .duration_trunc(Duration::seconds(TEMP_FILE_LIFETIME))
             .unwrap();
         let path = format!("{}/writer/{}.metric.tmp", metrics_root,id);
         let current_file = File::create(path)?;
+        let index = MetricIndex::load(&metrics_root, id)?;
         Ok(Self {
             id,
             metrics_root,
             current_time_slice: trunc_time,
             current_file,
+            index,
+            current_index: MetricIndex::default(),
+            current_summary: SegmentSummary::default(),
+            cache,
         })
     }
 
     pub fn run(&mut self, receiver: crossbeam_channel::Receiver<Metric>) -> io::Result<()> {
         loop {
-            self.check_file_swap()?;
             match receiver.recv_timeout(std::time::Duration::from_secs(5)) {
                 Ok(metric) => {
                     self.handle_metric(metric)?;
                 }
-                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Timeout) => {
+                    self.check_file_swap(chrono::Utc::now())?;
+                }
                 Err(RecvTimeoutError::Disconnected) => {
                     warn!("Error while receiving metric! Are we shutting down?");
                     return Ok(());
@@ -70,14 +87,19 @@ impl MetricWriter {
     }
 
     fn handle_metric(&mut self, mut metric: Metric) -> io::Result<()> {
-        metric.timestamp = Some(chrono::Utc::now());
+        let timestamp = chrono::Utc::now();
+        // Swapping with the same timestamp we write guarantees each segment only holds
+        // metrics from its own time slice
+        self.check_file_swap(timestamp)?;
+        metric.timestamp = Some(timestamp);
         debug!("Writing metric {:?}", metric);
         metric.write_to(&mut self.current_file)?;
+        self.current_index.record(&metric.metric_id, timestamp);
+        self.current_summary.record(&metric.metric_id, metric.value, timestamp);
         Ok(())
     }
 
-    fn check_file_swap(&mut self) -> io::Result<()> {
-        let time = chrono::Utc::now();
+    fn check_file_swap(&mut self, time: DateTime<Utc>) -> io::Result<()> {
         let trunc_time = time
             .duration_trunc(Duration::seconds(TEMP_FILE_LIFETIME))
             .unwrap();
@@ -89,9 +111,19 @@ impl MetricWriter {
                 self.id,
                 self.current_time_slice.timestamp()
             );
+            self.current_summary.write_footer(&mut self.current_file)?;
+            // The filter is written first, so it exists once the segment is visible
+            BloomFilter::from_ids(self.current_summary.metrics.keys()).save(&bloom_path(&new_path))?;
+            self.current_summary.clear();
             std::fs::rename(&old_path, &new_path)?;
+            self.cache.lock().unwrap().publish(self.current_time_slice.timestamp());
             self.current_file = File::create(old_path)?;
             self.current_time_slice = trunc_time;
+            if !self.current_index.is_empty() {
+                self.index.merge(&self.current_index);
+                self.current_index.clear();
+                self.index.save(&self.metrics_root, self.id)?;
+            }
         }
         Ok(())
     }
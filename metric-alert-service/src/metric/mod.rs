@@ -1,22 +1,73 @@
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::io;
 use std::io::{Read, Write};
 use crossbeam_channel::Sender;
+use crate::alarm::admin::AlarmAction;
+use crate::metric::aggregate::PartialResult;
+use crate::metric::catalog::CatalogQuery;
+use crate::metric::expression::ExpressionQuery;
 use crate::metric::query::QueryParams;
+use crate::metric::query_handler::QueryControl;
+use crate::metric::time::from_timestamp;
 
+pub mod aggregate;
+pub mod bloom;
+pub mod cache;
+pub mod catalog;
+pub mod expression;
+pub mod hash;
 pub mod metric_writer;
+pub mod parser;
 pub mod query_handler;
 pub mod query;
+pub mod reshard;
+pub mod router;
+pub mod segment;
+pub mod time;
 
-/// A query is a tuple with the query parameters and a sender (an address) to write the result
-pub type Query = (QueryParams, Sender<Vec<f32>>);
+/// Rotated segments hold the metrics written during this many seconds
+pub(crate) const TEMP_FILE_LIFETIME: i64 = 5;
+
+/// A query is a tuple with the query parameters, a sender (an address) to write the result and
+/// the control used to stop it
+pub type Query = (QueryParams, Sender<QueryMessage>, QueryControl);
 pub type DateRange = (DateTime<Utc>, DateTime<Utc>);
 
+/// Messages sent by a query handler while running a query
+#[derive(Debug)]
+pub enum QueryMessage {
+    /// Results of some segments. Every sample the handler will send later is newer than
+    /// `watermark`
+    Chunk {
+        shard: usize,
+        result: PartialResult,
+        watermark: i64,
+    },
+    Done {
+        shard: usize,
+    },
+    Error {
+        shard: usize,
+        message: String,
+    },
+    /// The deadline passed before the handler went through every segment
+    TimedOut {
+        shard: usize,
+    },
+}
+
 #[derive(Deserialize, Serialize)]
 pub enum MetricAction {
     Insert(Metric),
     Query(QueryParams),
+    Expression(ExpressionQuery),
+    /// Query written in the text query language, parsed by the server
+    Text(String),
+    /// Lists the stored metrics
+    Catalog(CatalogQuery),
+    /// Manages the alarms, with the admin token of the server
+    Alarm { token: String, action: AlarmAction },
 }
 
 impl MetricAction {
@@ -26,6 +77,14 @@ impl MetricAction {
         match action_code[0] {
             b'I' => Ok(MetricAction::Insert(Metric::from_stream(&mut stream)?)),
             b'Q' => Ok(MetricAction::Query(QueryParams::from_stream(&mut stream)?)),
+            b'E' => Ok(MetricAction::Expression(ExpressionQuery::from_stream(&mut stream)?)),
+            b'T' => Ok(MetricAction::Text(read_string(&mut stream)?)),
+            b'C' => Ok(MetricAction::Catalog(CatalogQuery::from_stream(&mut stream)?)),
+            b'A' => {
+                let token = read_string(&mut stream)?;
+                let action = AlarmAction::from_stream(&mut stream)?;
+                Ok(MetricAction::Alarm { token, action })
+            }
             _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid code")),
         }
     }
@@ -33,13 +92,30 @@ impl MetricAction {
     pub fn write_to<W: Write>(&self, stream: &mut W) -> io::Result<()> {
         match self {
             MetricAction::Insert(metric) => {
-                stream.write_all(&[b'I'])?;
+                stream.write_all(b"I")?;
                 metric.write_to(stream)?;
             }
             MetricAction::Query(query) => {
-                stream.write_all(&[b'Q'])?;
+                stream.write_all(b"Q")?;
+                query.write_to(stream)?;
+            }
+            MetricAction::Expression(query) => {
+                stream.write_all(b"E")?;
+                query.write_to(stream)?;
+            }
+            MetricAction::Text(text) => {
+                stream.write_all(b"T")?;
+                write_string(stream, text)?;
+            }
+            MetricAction::Catalog(query) => {
+                stream.write_all(b"C")?;
                 query.write_to(stream)?;
             }
+            MetricAction::Alarm { token, action } => {
+                stream.write_all(b"A")?;
+                write_string(stream, token)?;
+                action.write_to(stream)?;
+            }
         }
         Ok(())
     }
@@ -54,25 +130,18 @@ pub struct Metric {
 
 impl Metric {
     pub fn from_stream<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let metric_id = read_string(stream)?;
         let mut size_buf = [0; 4];
         stream.read_exact(&mut size_buf)?;
-        let size = u32::from_be_bytes(size_buf);
-        let mut metric_id_buf = vec![0u8; size as usize];
-        stream.read_exact(metric_id_buf.as_mut_slice())?;
-        let metric_id = String::from_utf8(metric_id_buf).unwrap();
-        stream.read_exact(&mut size_buf)?;
         let value = f32::from_be_bytes(size_buf);
         let mut timestamp_buf = [0; 8];
         stream.read_exact(&mut timestamp_buf)?;
-        let timestamp_i64 = i64::from_be_bytes(timestamp_buf);
-        let naive = NaiveDateTime::from_timestamp(timestamp_i64, 0);
-        let timestamp = Some(DateTime::from_utc(naive, Utc));
+        let timestamp = Some(from_timestamp(i64::from_be_bytes(timestamp_buf))?);
         Ok(Self { metric_id, value, timestamp })
     }
 
     pub fn write_to<W: Write>(&self, stream: &mut W) -> io::Result<()> {
-        stream.write_all(&(self.metric_id.len() as u32).to_be_bytes())?;
-        stream.write_all(self.metric_id.as_bytes())?;
+        write_string(stream, &self.metric_id)?;
         stream.write_all(&self.value.to_be_bytes())?;
         if let Some(timestamp) = self.timestamp {
             stream.write_all(&timestamp.timestamp().to_be_bytes())?;
@@ -83,20 +152,17 @@ impl Metric {
     }
 }
 
-pub struct MetricIterator<R: Read> {
-    source: R,
-}
-
-impl<R: Read> MetricIterator<R> {
-    pub fn new(source: R) -> Self {
-        Self { source }
-    }
+/// Reads a string with its length prepended as a big endian u32
+pub(crate) fn read_string<R: Read>(stream: &mut R) -> io::Result<String> {
+    let mut size_buf = [0; 4];
+    stream.read_exact(&mut size_buf)?;
+    let size = u32::from_be_bytes(size_buf);
+    let mut string_buf = vec![0u8; size as usize];
+    stream.read_exact(string_buf.as_mut_slice())?;
+    String::from_utf8(string_buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
 
-impl<R: Read> Iterator for MetricIterator<R> {
-    type Item = Metric;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        Metric::from_stream(&mut self.source).ok()
-    }
+pub(crate) fn write_string<W: Write>(stream: &mut W, string: &str) -> io::Result<()> {
+    stream.write_all(&(string.len() as u32).to_be_bytes())?;
+    stream.write_all(string.as_bytes())
 }
@@ -0,0 +1,575 @@
+//! Text query language, translated into the same actions sent over the wire.
+//!
+//! ```text
+//! query       := [("top" | "bottom") integer] expression ["by" duration] ["combined"] [timeout]
+//! timeout     := "timeout" duration ["partial"]
+//! expression  := term (("+" | "-") term)*
+//! term        := factor (("*" | "/") factor)*
+//! factor      := number | call | "(" expression ")"
+//! aggregation := "avg" | "min" | "max" | "count" | "p" number
+//! call        := aggregation "(" selector ["[" time ["," time] "]"] ")"
+//! selector    := metric_id | pattern_with_wildcards | "/" regex "/"
+//! time        := "now" | "now-" duration | RFC 3339 date | YYYY-MM-DDTHH:MM:SS
+//! duration    := number ["s" | "m" | "h" | "d"]
+//! ```
+//!
+//! For example `avg(metric_1[2022-05-07T21:18:00, now]) by 10s` or `max(metric_1[now-5m]) by 1m`,
+//! where a range with a single time ends now. A single call is a plain query, anything else is
+//! an expression whose operands must share the same date range. Rankings like
+//! `top 10 max(api.*.latency[now-15m])` only apply to a single call. Timeouts apply to both,
+//! and `partial` makes a single call return the windows computed before its timeout, as in
+//! `avg(metric_1[now-1d]) by 1h timeout 2s partial`.
+use crate::metric::expression::{Expression, ExpressionQuery, Operator, MAX_EXPRESSION_DEPTH, MAX_EXPRESSION_OPERANDS};
+use crate::metric::query::{MetricSelector, QueryAggregation, QueryParams, RankOrder, Ranking};
+use crate::metric::time;
+use crate::metric::time::{TimePoint, TimeRange};
+use crate::metric::MetricAction;
+use std::fmt;
+
+/// Error found while parsing a query, with the column (starting at 1) where it was found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub column: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    fn new(position: usize, message: impl Into<String>) -> Self {
+        Self {
+            column: position + 1,
+            message: message.into(),
+        }
+    }
+
+    /// Renders the error below the query, with a marker pointing at the offending column
+    pub fn highlight(&self, input: &str) -> String {
+        format!("{}\n{}\n{}^", self, input, " ".repeat(self.column - 1))
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at column {}", self.message, self.column)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parses a text query into a query or expression action
+pub fn parse(input: &str) -> Result<MetricAction, ParseError> {
+    let mut parser = Parser {
+        input: input.chars().collect(),
+        position: 0,
+        depth: 0,
+    };
+    parser.parse_query()
+}
+
+struct Call {
+    aggregation: QueryAggregation,
+    metric_id: String,
+    selector: MetricSelector,
+    date_range: Option<TimeRange>,
+    position: usize,
+}
+
+enum Node {
+    Call(Call),
+    Constant(f32),
+    Binary(Box<Node>, Operator, Box<Node>),
+}
+
+struct Parser {
+    input: Vec<char>,
+    position: usize,
+    /// Parentheses open at the current position
+    depth: usize,
+}
+
+impl Parser {
+    fn parse_query(&mut self) -> Result<MetricAction, ParseError> {
+        let ranking_position = self.position;
+        let ranking = self.parse_ranking()?;
+        let node = self.parse_expression()?;
+        let mut window_secs = 0.0;
+        let mut combine = false;
+        if self.next_word_is("by") {
+            self.read_word();
+            window_secs = self.parse_duration()?;
+        }
+        if self.next_word_is("combined") {
+            self.read_word();
+            combine = true;
+        }
+        let mut timeout = None;
+        let mut partial = None;
+        if self.next_word_is("timeout") {
+            let position = self.position;
+            self.read_word();
+            let timeout_ms = (self.parse_duration()? * 1000.0) as u64;
+            if timeout_ms == 0 {
+                return Err(ParseError::new(position, "the timeout must be at least 1ms"));
+            }
+            timeout = Some((position, timeout_ms));
+            if self.next_word_is("partial") {
+                partial = Some(self.position);
+                self.read_word();
+            }
+        }
+        self.skip_whitespace();
+        if let Some(c) = self.peek() {
+            return Err(ParseError::new(self.position, format!("unexpected '{}'", c)));
+        }
+        match node {
+            Node::Call(call) => Ok(MetricAction::Query(QueryParams {
+                metric_id: call.metric_id,
+                selector: call.selector,
+                combine,
+                date_range: call.date_range,
+                aggregation: call.aggregation,
+                window_secs,
+                ranking,
+                timeout_ms: timeout.map(|(_, timeout_ms)| timeout_ms),
+                partial: partial.is_some(),
+            })),
+            _ if ranking.is_some() => Err(ParseError::new(
+                ranking_position,
+                "rankings can't be used with expressions",
+            )),
+            _ if partial.is_some() => Err(ParseError::new(
+                partial.unwrap_or(0),
+                "partial results can't be used with expressions",
+            )),
+            node => {
+                let mut date_range = None;
+                let expression = into_expression(node, &mut date_range)?;
+                Ok(MetricAction::Expression(ExpressionQuery {
+                    expression,
+                    date_range: date_range.flatten(),
+                    window_secs,
+                    timeout_ms: timeout.map(|(_, timeout_ms)| timeout_ms),
+                }))
+            }
+        }
+    }
+
+    fn parse_ranking(&mut self) -> Result<Option<Ranking>, ParseError> {
+        let order = if self.next_word_is("top") {
+            RankOrder::Top
+        } else if self.next_word_is("bottom") {
+            RankOrder::Bottom
+        } else {
+            return Ok(None);
+        };
+        self.read_word();
+        self.skip_whitespace();
+        let start = self.position;
+        let text = self.read_while(|c| c.is_ascii_digit());
+        let limit = text
+            .parse()
+            .map_err(|_| ParseError::new(start, "expected the number of metrics to rank"))?;
+        Ok(Some(Ranking { order, limit }))
+    }
+
+    fn parse_expression(&mut self) -> Result<Node, ParseError> {
+        let mut node = self.parse_term()?;
+        loop {
+            self.skip_whitespace();
+            let operator = match self.peek() {
+                Some('+') => Operator::Add,
+                Some('-') => Operator::Sub,
+                _ => return Ok(node),
+            };
+            let position = self.position;
+            self.position += 1;
+            let right = self.parse_term()?;
+            node = binary(position, node, operator, right)?;
+        }
+    }
+
+    fn parse_term(&mut self) -> Result<Node, ParseError> {
+        let mut node = self.parse_factor()?;
+        loop {
+            self.skip_whitespace();
+            let operator = match self.peek() {
+                Some('*') => Operator::Mul,
+                Some('/') => Operator::Div,
+                _ => return Ok(node),
+            };
+            let position = self.position;
+            self.position += 1;
+            let right = self.parse_factor()?;
+            node = binary(position, node, operator, right)?;
+        }
+    }
+
+    fn parse_factor(&mut self) -> Result<Node, ParseError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('(') => {
+                if self.depth >= MAX_EXPRESSION_DEPTH {
+                    return Err(ParseError::new(self.position, "the expression is nested too deeply"));
+                }
+                self.position += 1;
+                self.depth += 1;
+                let node = self.parse_expression()?;
+                self.depth -= 1;
+                self.expect(')')?;
+                Ok(node)
+            }
+            Some(c) if c.is_ascii_digit() || c == '-' || c == '.' => {
+                Ok(Node::Constant(self.parse_number()?))
+            }
+            Some(c) if c.is_ascii_alphabetic() => Ok(Node::Call(self.parse_call()?)),
+            Some(c) => Err(ParseError::new(self.position, format!("unexpected '{}'", c))),
+            None => Err(ParseError::new(self.position, "unexpected end of query")),
+        }
+    }
+
+    fn parse_call(&mut self) -> Result<Call, ParseError> {
+        let position = self.position;
+        let mut name = self.read_word();
+        // Percentiles may have decimals, like p99.9
+        if self.peek() == Some('.') && name.len() > 1 && name.starts_with(['p', 'P']) {
+            self.position += 1;
+            name = format!("{}.{}", name, self.read_while(|c| c.is_ascii_digit()));
+        }
+        let aggregation = match name.to_ascii_lowercase().as_str() {
+            "avg" => QueryAggregation::Avg,
+            "min" => QueryAggregation::Min,
+            "max" => QueryAggregation::Max,
+            "count" => QueryAggregation::Count,
+            lowercase => match lowercase.strip_prefix('p').map(str::parse::<f32>) {
+                Some(Ok(percentile)) if (0.0..=100.0).contains(&percentile) => {
+                    QueryAggregation::Percentile(percentile)
+                }
+                _ => {
+                    return Err(ParseError::new(
+                        position,
+                        format!("unknown aggregation '{}', expected avg, min, max, count or a percentile like p99", name),
+                    ))
+                }
+            },
+        };
+        self.expect('(')?;
+        self.skip_whitespace();
+        let (metric_id, selector) = self.parse_selector()?;
+        self.skip_whitespace();
+        let date_range = if self.peek() == Some('[') {
+            self.position += 1;
+            let from = self.parse_time(',')?;
+            self.skip_whitespace();
+            let to = if self.peek() == Some(',') {
+                self.position += 1;
+                self.parse_time(']')?
+            } else {
+                TimePoint::Relative(0)
+            };
+            self.expect(']')?;
+            Some(TimeRange(from, to))
+        } else {
+            None
+        };
+        self.expect(')')?;
+        Ok(Call {
+            aggregation,
+            metric_id,
+            selector,
+            date_range,
+            position,
+        })
+    }
+
+    fn parse_selector(&mut self) -> Result<(String, MetricSelector), ParseError> {
+        let start = self.position;
+        if self.peek() == Some('/') {
+            self.position += 1;
+            let mut regex = String::new();
+            loop {
+                match self.peek() {
+                    Some('/') => break,
+                    Some('\\') if self.input.get(self.position + 1) == Some(&'/') => {
+                        regex.push('/');
+                        self.position += 2;
+                    }
+                    Some(c) => {
+                        regex.push(c);
+                        self.position += 1;
+                    }
+                    None => return Err(ParseError::new(start, "unterminated regex")),
+                }
+            }
+            self.position += 1;
+            if let Err(e) = regex::Regex::new(&regex) {
+                return Err(ParseError::new(start, format!("invalid regex: {}", e)));
+            }
+            return Ok((regex, MetricSelector::Regex));
+        }
+        let metric_id = self.read_while(|c| !c.is_whitespace() && !"[](),".contains(c));
+        if metric_id.is_empty() {
+            return Err(ParseError::new(start, "expected metric id"));
+        }
+        let selector = if metric_id.contains('*') {
+            MetricSelector::Wildcard
+        } else {
+            MetricSelector::Exact
+        };
+        Ok((metric_id, selector))
+    }
+
+    fn parse_time(&mut self, terminator: char) -> Result<TimePoint, ParseError> {
+        self.skip_whitespace();
+        let start = self.position;
+        let text = self.read_while(|c| c != terminator && c != ']' && c != ')');
+        text.trim().parse().map_err(|e| ParseError::new(start, e))
+    }
+
+    fn parse_duration(&mut self) -> Result<f32, ParseError> {
+        self.skip_whitespace();
+        let start = self.position;
+        let text = self.read_while(|c| c.is_alphanumeric() || c == '.');
+        time::parse_duration(&text).map_err(|e| ParseError::new(start, e))
+    }
+
+    fn parse_number(&mut self) -> Result<f32, ParseError> {
+        let start = self.position;
+        let mut text = String::new();
+        if self.peek() == Some('-') {
+            text.push('-');
+            self.position += 1;
+        }
+        text.push_str(&self.read_while(|c| c.is_ascii_digit() || c == '.'));
+        text.parse()
+            .map_err(|_| ParseError::new(start, format!("invalid number '{}'", text)))
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.position += 1;
+                Ok(())
+            }
+            Some(c) => Err(ParseError::new(
+                self.position,
+                format!("expected '{}', found '{}'", expected, c),
+            )),
+            None => Err(ParseError::new(
+                self.position,
+                format!("expected '{}', found end of query", expected),
+            )),
+        }
+    }
+
+    fn next_word_is(&mut self, word: &str) -> bool {
+        self.skip_whitespace();
+        let end = self.position + word.len();
+        end <= self.input.len()
+            && self.input[self.position..end].iter().copied().eq(word.chars())
+            && !self.input.get(end).is_some_and(|c| c.is_alphanumeric())
+    }
+
+    fn read_word(&mut self) -> String {
+        self.read_while(|c| c.is_alphanumeric() || c == '_')
+    }
+
+    fn read_while(&mut self, condition: impl Fn(char) -> bool) -> String {
+        let start = self.position;
+        while self.peek().is_some_and(&condition) {
+            self.position += 1;
+        }
+        self.input[start..self.position].iter().collect()
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.read_while(char::is_whitespace);
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input.get(self.position).copied()
+    }
+}
+
+impl Node {
+    fn depth(&self) -> usize {
+        match self {
+            Node::Call(_) | Node::Constant(_) => 0,
+            Node::Binary(left, _, right) => 1 + left.depth().max(right.depth()),
+        }
+    }
+
+    fn operands(&self) -> usize {
+        match self {
+            Node::Call(_) | Node::Constant(_) => 1,
+            Node::Binary(left, _, right) => left.operands() + right.operands(),
+        }
+    }
+}
+
+/// Joins two operands, within the depth and size expressions are allowed to have on the wire
+fn binary(position: usize, left: Node, operator: Operator, right: Node) -> Result<Node, ParseError> {
+    let node = Node::Binary(Box::new(left), operator, Box::new(right));
+    if node.depth() > MAX_EXPRESSION_DEPTH {
+        return Err(ParseError::new(position, "the expression is nested too deeply"));
+    }
+    if node.operands() > MAX_EXPRESSION_OPERANDS {
+        return Err(ParseError::new(position, "the expression has too many operands"));
+    }
+    Ok(node)
+}
+
+/// Converts a parsed node into an expression, checking every call shares the same date range
+fn into_expression(
+    node: Node,
+    date_range: &mut Option<Option<TimeRange>>,
+) -> Result<Expression, ParseError> {
+    match node {
+        Node::Call(call) => {
+            if call.selector != MetricSelector::Exact {
+                return Err(ParseError::new(
+                    call.position,
+                    "patterns can't be used inside expressions",
+                ));
+            }
+            match date_range {
+                None => *date_range = Some(call.date_range),
+                Some(range) if *range != call.date_range => {
+                    return Err(ParseError::new(
+                        call.position,
+                        "all the metrics of an expression must use the same date range",
+                    ))
+                }
+                Some(_) => {}
+            }
+            Ok(Expression::Metric {
+                metric_id: call.metric_id,
+                aggregation: call.aggregation,
+            })
+        }
+        Node::Constant(value) => Ok(Expression::Constant(value)),
+        Node::Binary(left, operator, right) => Ok(Expression::Binary(
+            Box::new(into_expression(*left, date_range)?),
+            operator,
+            Box::new(into_expression(*right, date_range)?),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Renders an expression with every binary operation in parentheses
+    fn render(expression: &Expression) -> String {
+        match expression {
+            Expression::Metric { metric_id, aggregation } => format!("{:?}({})", aggregation, metric_id),
+            Expression::Constant(value) => value.to_string(),
+            Expression::Binary(left, operator, right) => {
+                format!("({} {:?} {})", render(left), operator, render(right))
+            }
+        }
+    }
+
+    fn expression(input: &str) -> String {
+        match parse(input) {
+            Ok(MetricAction::Expression(query)) => render(&query.expression),
+            _ => panic!("{:?} isn't an expression", input),
+        }
+    }
+
+    fn error(input: &str) -> ParseError {
+        match parse(input) {
+            Err(e) => e,
+            Ok(_) => panic!("{:?} was parsed", input),
+        }
+    }
+
+    #[test]
+    fn products_bind_tighter_than_sums() {
+        assert_eq!(expression("avg(a) + avg(b) * 2"), "(Avg(a) Add (Avg(b) Mul 2))");
+        assert_eq!(expression("(avg(a) + avg(b)) * 2"), "((Avg(a) Add Avg(b)) Mul 2)");
+        assert_eq!(expression("max(a) / count(b) - 1"), "((Max(a) Div Count(b)) Sub 1)");
+    }
+
+    #[test]
+    fn operators_are_left_associative() {
+        assert_eq!(expression("avg(a) - avg(b) - avg(c)"), "((Avg(a) Sub Avg(b)) Sub Avg(c))");
+        assert_eq!(expression("avg(a) / 2 / 4"), "((Avg(a) Div 2) Div 4)");
+    }
+
+    #[test]
+    fn single_calls_are_plain_queries() {
+        let Ok(MetricAction::Query(query)) = parse("top 3 max(api.*[now-15m]) by 1m timeout 2s partial") else {
+            panic!("not a query");
+        };
+        assert_eq!(query.metric_id, "api.*");
+        assert_eq!(query.selector, MetricSelector::Wildcard);
+        assert_eq!(query.window_secs, 60.0);
+        assert_eq!(query.ranking.map(|ranking| ranking.limit), Some(3));
+        assert_eq!(query.timeout_ms, Some(2000));
+        assert!(query.partial);
+        assert_eq!(
+            query.date_range,
+            Some(TimeRange(TimePoint::Relative(-900), TimePoint::Relative(0)))
+        );
+    }
+
+    #[test]
+    fn expressions_take_timeouts() {
+        let Ok(MetricAction::Expression(query)) = parse("avg(a) / avg(b) by 1m timeout 2s") else {
+            panic!("not an expression");
+        };
+        assert_eq!(query.window_secs, 60.0);
+        assert_eq!(query.timeout_ms, Some(2000));
+    }
+
+    #[test]
+    fn parses_percentiles() {
+        assert_eq!(expression("p99(a) - p50(a)"), "(Percentile(99.0)(a) Sub Percentile(50.0)(a))");
+        assert_eq!(expression("P99.9(a) * 2"), "(Percentile(99.9)(a) Mul 2)");
+        assert!(error("p101(a)").message.contains("unknown aggregation"));
+        assert!(error("p(a)").message.contains("unknown aggregation"));
+    }
+
+    #[test]
+    fn errors_point_at_their_column() {
+        let e = error("median(a)");
+        assert_eq!(e.column, 1);
+        assert!(e.message.contains("unknown aggregation"));
+        assert_eq!(error("avg(a) + ").message, "unexpected end of query");
+        assert_eq!(error("avg(a) ]").column, 8);
+        assert_eq!(error("avg(a[now-5m)").message, "expected ']', found ')'");
+    }
+
+    #[test]
+    fn rejects_what_expressions_cant_do() {
+        assert!(error("top 3 avg(a) + avg(b)").message.contains("rankings"));
+        assert!(error("avg(a) + avg(b) timeout 1s partial").message.contains("partial"));
+        assert!(error("avg(a.*) + 1").message.contains("patterns"));
+        assert!(error("avg(a[now-5m]) + avg(b)").message.contains("same date range"));
+        assert!(error("avg(a) timeout 0s").message.contains("at least 1ms"));
+    }
+
+    #[test]
+    fn limits_the_depth_of_expressions() {
+        let nested = format!("{}avg(a){}", "(".repeat(100_000), ")".repeat(100_000));
+        assert!(error(&nested).message.contains("nested too deeply"));
+        let chain = vec!["avg(a)"; 100_000].join(" + ");
+        assert!(error(&chain).message.contains("nested too deeply"));
+        let allowed = vec!["avg(a)"; MAX_EXPRESSION_DEPTH + 1].join(" + ");
+        assert!(parse(&allowed).is_ok());
+    }
+
+    #[test]
+    fn limits_the_operands_of_expressions() {
+        let balanced = |leaves: usize| {
+            let mut level = vec!["avg(a)".to_string(); leaves];
+            while level.len() > 1 {
+                level = level.chunks(2).map(|pair| format!("({} + {})", pair[0], pair[1])).collect();
+            }
+            level.remove(0)
+        };
+        assert!(parse(&balanced(MAX_EXPRESSION_OPERANDS)).is_ok());
+        assert!(error(&balanced(MAX_EXPRESSION_OPERANDS * 2)).message.contains("too many operands"));
+    }
+}
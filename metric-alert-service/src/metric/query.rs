@@ -1,194 +1,522 @@
-use chrono::{DateTime, Duration, MIN_DATETIME, NaiveDateTime, Utc};
+use chrono::{DateTime, Utc};
 use log::debug;
-use serde::{Deserialize, Deserializer, Serialize};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
 use std::io;
 use std::io::{BufReader, Read, Write};
-use std::ops::Sub;
-use crate::metric::{DateRange, Metric};
+use crate::metric::aggregate::{AggregateState, PartialResult, Windows};
+use crate::metric::segment::{Record, SegmentSummary};
+use crate::metric::time::{read_time_range, write_time_range, TimeRange};
+use crate::metric::{read_string, write_string, DateRange};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct QueryParams {
     pub metric_id: String,
+    /// How `metric_id` is interpreted
+    #[serde(default)]
+    pub selector: MetricSelector,
+    /// If the selector matches several metrics, aggregate all of them into a single series
     #[serde(default)]
-    #[serde(deserialize_with = "date_deserializer")]
-    pub date_range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    pub combine: bool,
+    #[serde(default)]
+    pub date_range: Option<TimeRange>,
     pub aggregation: QueryAggregation,
     pub window_secs: f32,
+    /// Ranks the selected metrics by their aggregation over the whole range, ignoring windows
+    #[serde(default)]
+    pub ranking: Option<Ranking>,
+    /// Time in milliseconds the server may spend on the query before giving up
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// On timeout, answer with the values computed so far instead of an error
+    #[serde(default)]
+    pub partial: bool,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Ranking {
+    pub order: RankOrder,
+    pub limit: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum RankOrder {
+    /// Metrics with the highest values first
+    Top,
+    /// Metrics with the lowest values first
+    Bottom,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub enum QueryAggregation {
     Avg,
     Min,
     Max,
     Count,
+    /// Value below which this percent of the samples are, like 99 for the p99
+    Percentile(f32),
+}
+
+impl QueryAggregation {
+    pub fn from_stream<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let mut aggregation_buf = [0];
+        stream.read_exact(&mut aggregation_buf)?;
+        match aggregation_buf[0] {
+            b'a' => Ok(QueryAggregation::Avg),
+            b'm' => Ok(QueryAggregation::Min),
+            b'M' => Ok(QueryAggregation::Max),
+            b'c' => Ok(QueryAggregation::Count),
+            b'p' => {
+                let mut percentile_buf = [0; 4];
+                stream.read_exact(&mut percentile_buf)?;
+                let aggregation = QueryAggregation::Percentile(f32::from_be_bytes(percentile_buf));
+                aggregation
+                    .validate()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Ok(aggregation)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid aggregation",
+            )),
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        let aggregation_code = match self {
+            QueryAggregation::Avg => b'a',
+            QueryAggregation::Min => b'm',
+            QueryAggregation::Max => b'M',
+            QueryAggregation::Count => b'c',
+            QueryAggregation::Percentile(_) => b'p',
+        };
+        stream.write_all(&[aggregation_code])?;
+        if let QueryAggregation::Percentile(percentile) = self {
+            stream.write_all(&percentile.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            QueryAggregation::Percentile(percentile) if !(0.0..=100.0).contains(percentile) => {
+                Err(format!("the percentile {} isn't between 0 and 100", percentile))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the aggregation needs the histogram of the values
+    pub fn needs_histogram(&self) -> bool {
+        matches!(self, QueryAggregation::Percentile(_))
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub enum MetricSelector {
+    /// Selects the metric with exactly the same id
+    #[default]
+    Exact,
+    /// Selects metrics matching a pattern where `*` stands for any sequence of characters
+    /// except `.`, like `api.*.latency`
+    Wildcard,
+    /// Selects metrics matching a regular expression
+    Regex,
 }
 
 impl QueryParams {
     pub fn from_stream<R: Read>(stream: R) -> io::Result<Self> {
         let mut reader = BufReader::new(stream);
-        let mut size_buf = [0; 4];
-        reader.read_exact(&mut size_buf)?;
-        let size = u32::from_be_bytes(size_buf);
-        let mut metric_id_buf = vec![0u8; size as usize];
-        reader.read_exact(metric_id_buf.as_mut_slice())?;
-        let metric_id = String::from_utf8(metric_id_buf).unwrap();
-        let mut has_time_range = [0];
-        reader.read_exact(&mut has_time_range)?;
-        let mut date_range = None;
-        if has_time_range[0] == b'Y' {
-            let mut timestamp_buf = [0; 8];
-            reader.read_exact(timestamp_buf.as_mut_slice())?;
-            let from_ts = i64::from_be_bytes(timestamp_buf);
-            reader.read_exact(timestamp_buf.as_mut_slice())?;
-            let to_ts = i64::from_be_bytes(timestamp_buf);
-            let from = DateTime::from_utc(NaiveDateTime::from_timestamp(from_ts, 0), Utc);
-            let to = DateTime::from_utc(NaiveDateTime::from_timestamp(to_ts, 0), Utc);
-            date_range = Some((from, to));
-        } else if has_time_range[0] != b'N' {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid code"));
-        }
+        let metric_id = read_string(&mut reader)?;
+        let date_range = read_time_range(&mut reader)?;
+        let aggregation = QueryAggregation::from_stream(&mut reader)?;
+        let mut window_buf = [0; 4];
+        reader.read_exact(&mut window_buf)?;
+        let window_secs = f32::from_be_bytes(window_buf);
 
-        let mut aggregation_buf = [0];
-        reader.read_exact(&mut aggregation_buf)?;
-        let aggregation = match aggregation_buf[0] {
-            b'a' => QueryAggregation::Avg,
-            b'm' => QueryAggregation::Min,
-            b'M' => QueryAggregation::Max,
-            b'c' => QueryAggregation::Count,
-            _ => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Invalid aggregation",
-                ))
-            }
+        let mut selector_buf = [0; 2];
+        reader.read_exact(&mut selector_buf)?;
+        let selector = match selector_buf[0] {
+            b'e' => MetricSelector::Exact,
+            b'w' => MetricSelector::Wildcard,
+            b'r' => MetricSelector::Regex,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid selector")),
+        };
+        let combine = selector_buf[1] == b'Y';
+
+        let mut ranking_buf = [0];
+        reader.read_exact(&mut ranking_buf)?;
+        let order = match ranking_buf[0] {
+            b'T' => Some(RankOrder::Top),
+            b'B' => Some(RankOrder::Bottom),
+            b'N' => None,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid ranking")),
         };
+        let mut ranking = None;
+        if let Some(order) = order {
+            let mut limit_buf = [0; 4];
+            reader.read_exact(&mut limit_buf)?;
+            let limit = u32::from_be_bytes(limit_buf);
+            ranking = Some(Ranking { order, limit });
+        }
 
-        reader.read_exact(&mut size_buf)?;
-        let window_secs = f32::from_be_bytes(size_buf);
-        Ok(Self {
+        let mut timeout_buf = [0; 8];
+        reader.read_exact(&mut timeout_buf)?;
+        let timeout_ms = Some(u64::from_be_bytes(timeout_buf)).filter(|timeout| *timeout > 0);
+        let mut partial_buf = [0];
+        reader.read_exact(&mut partial_buf)?;
+        let partial = partial_buf[0] == b'Y';
+        let query = Self {
             metric_id,
+            selector,
+            combine,
             date_range,
             aggregation,
             window_secs,
-        })
+            ranking,
+            timeout_ms,
+            partial,
+        };
+        MetricMatcher::new(&query)?;
+        Ok(query)
     }
 
     pub fn write_to<W: Write>(&self, stream: &mut W) -> io::Result<()> {
-        stream.write_all(&(self.metric_id.len() as u32).to_be_bytes())?;
-        stream.write_all(self.metric_id.as_bytes())?;
-        if let Some((from, to)) = self.date_range {
-            stream.write_all(&[b'Y'])?;
-            stream.write_all(&from.timestamp().to_be_bytes())?;
-            stream.write_all(&to.timestamp().to_be_bytes())?;
-        } else {
-            stream.write_all(&[b'N'])?;
-        }
-        let aggregation_code = match self.aggregation {
-            QueryAggregation::Avg => b'a',
-            QueryAggregation::Min => b'm',
-            QueryAggregation::Max => b'M',
-            QueryAggregation::Count => b'c',
-        };
-        stream.write_all(&[aggregation_code])?;
+        write_string(stream, &self.metric_id)?;
+        write_time_range(stream, &self.date_range)?;
+        self.aggregation.write_to(stream)?;
         stream.write_all(&self.window_secs.to_be_bytes())?;
+        let selector_code = match self.selector {
+            MetricSelector::Exact => b'e',
+            MetricSelector::Wildcard => b'w',
+            MetricSelector::Regex => b'r',
+        };
+        let combine_code = if self.combine { b'Y' } else { b'N' };
+        stream.write_all(&[selector_code, combine_code])?;
+        match &self.ranking {
+            Some(ranking) => {
+                let order_code = match ranking.order {
+                    RankOrder::Top => b'T',
+                    RankOrder::Bottom => b'B',
+                };
+                stream.write_all(&[order_code])?;
+                stream.write_all(&ranking.limit.to_be_bytes())?;
+            }
+            None => stream.write_all(b"N")?,
+        }
+        stream.write_all(&self.timeout_ms.unwrap_or(0).to_be_bytes())?;
+        stream.write_all(if self.partial { b"Y" } else { b"N" })?;
         Ok(())
     }
 
-    pub(crate) fn process_metrics(&self, metrics: impl Iterator<Item = Metric>) -> Vec<f32> {
-        debug!("Processing metrics...");
-        let mut result_vec = vec!();
-        let mut last_window_start = MIN_DATETIME;
-        if self.window_secs == 0.0 {
-            let values = metrics.map(|metric| metric.value);
-            let result = match self.aggregation {
-                QueryAggregation::Avg => {
-                    let mut count = 0;
-                    let mut sum = 0.0;
-                    values.for_each(|v| {
-                        sum += v;
-                        count += 1;
-                    });
-                    if count > 0 {
-                        Some(sum / count as f32)
-                    } else {
-                        None
-                    }
+    /// Replaces relative time points with absolute ones, so every handler running the query
+    /// uses the same range
+    pub fn resolve_time(&mut self, now: DateTime<Utc>) -> io::Result<()> {
+        self.date_range = self.date_range.map(|range| range.resolved(now)).transpose()?;
+        Ok(())
+    }
+
+    /// Aggregates the metrics selected by `matcher` inside the resolved `date_range`. Records
+    /// borrow their metric id from the mapped segment, so ids of other metrics are never copied
+    pub(crate) fn process_metrics<'a>(
+        &self,
+        matcher: &MetricMatcher,
+        date_range: Option<DateRange>,
+        records: impl Iterator<Item = Record<'a>>,
+    ) -> PartialResult {
+        debug!("Processing with window secs: {}", self.window_secs);
+        let histogram = self.aggregation.needs_histogram();
+        let mut result = PartialResult::default();
+        for record in records.filter(|record| matcher.matches(record.metric_id)) {
+            if let Some((from, to)) = date_range {
+                if record.timestamp < from || record.timestamp > to {
+                    continue;
                 }
-                // TODO: handle NaN values
-                QueryAggregation::Max => values.max_by(|l, r| l.partial_cmp(r).unwrap()),
-                QueryAggregation::Min => values.min_by(|l, r| l.partial_cmp(r).unwrap()),
-                QueryAggregation::Count => Some(values.count() as f32),
-            };
-            if let Some(value) = result {
-                result_vec.push(value);
             }
-        } else {
-            debug!("Processing with window secs: {}", self.window_secs);
-            let mut count = 0;
-            let mut sum = 0.0;
-            let mut min = 0.0;
-            let mut max = 0.0;
-            for metric in metrics {
-                debug!("Metric: {:?}", metric);
-                let timestamp = metric.timestamp.unwrap();
-                if timestamp.sub(Duration::seconds(self.window_secs as i64)) > last_window_start {
-                    if last_window_start != MIN_DATETIME {
-                        match self.aggregation {
-                            QueryAggregation::Avg => {
-                                if count > 0 {
-                                    result_vec.push(sum / count as f32);
-                                }
-                            }
-                            // TODO: handle NaN values
-                            QueryAggregation::Max => result_vec.push(max),
-                            QueryAggregation::Min => result_vec.push(min),
-                            QueryAggregation::Count => result_vec.push(count as f32),
-                        }
-                    };
-                    max = f32::MIN;
-                    min = f32::MAX;
-                    sum = 0.0;
-                    count = 0;
-                    last_window_start = timestamp;
+            result.push(record.metric_id, self.window_start(record.timestamp), record.value, histogram);
+        }
+        debug!("Finished metrics...");
+        result
+    }
+
+    /// Partial result of a segment computed from its summary alone. `None` if a selected metric
+    /// has samples outside the range or in several windows, or if the query needs histograms,
+    /// so the records must be read
+    pub(crate) fn process_summary(
+        &self,
+        matcher: &MetricMatcher,
+        date_range: Option<DateRange>,
+        summary: &SegmentSummary,
+    ) -> Option<PartialResult> {
+        if self.aggregation.needs_histogram() {
+            return None;
+        }
+        let mut result = PartialResult::default();
+        for (metric_id, metric) in summary.metrics.iter().filter(|(metric_id, _)| matcher.matches(metric_id)) {
+            if let Some((from, to)) = date_range {
+                if metric.first < from || metric.last > to {
+                    return None;
                 }
-                sum += metric.value;
-                count += 1;
-                max = f32::max(max, metric.value);
-                min = f32::min(min, metric.value);
             }
-            debug!("Finished metrics...");
-            if last_window_start != MIN_DATETIME {
-                match self.aggregation {
-                    QueryAggregation::Avg => {
-                        if count > 0 {
-                            result_vec.push(sum / count as f32);
-                        }
-                    }
-                    // TODO: handle NaN values
-                    QueryAggregation::Max => result_vec.push(max),
-                    QueryAggregation::Min => result_vec.push(min),
-                    QueryAggregation::Count => result_vec.push(count as f32),
-                }
+            let window_start = self.window_start(metric.first);
+            if window_start != self.window_start(metric.last) {
+                return None;
             }
+            result.push_state(metric_id, window_start, &metric.state);
+        }
+        Some(result)
+    }
+
+    /// Windows are aligned to multiples of `window_secs` since epoch, so results computed by
+    /// different shards can be merged. A `window_secs` of zero means a single window
+    fn window_start(&self, timestamp: DateTime<Utc>) -> i64 {
+        match self.window_length() {
+            Some(window_length) => timestamp.timestamp().div_euclid(window_length) * window_length,
+            None => 0,
+        }
+    }
+
+    /// Length of the windows in seconds, `None` if the query has a single window
+    pub fn window_length(&self) -> Option<i64> {
+        let window_secs = self.window_secs as i64;
+        if window_secs <= 0 || self.ranking.is_some() {
+            None
+        } else {
+            Some(window_secs)
+        }
+    }
+
+    /// Keeps the metrics of a partial result that make it to the ranking. Only valid for the
+    /// result of a handler that holds every sample of its metrics: a metric with samples in
+    /// several handlers may rank low in each of them and high once they are merged
+    pub(crate) fn retain_ranked(&self, mut partial: PartialResult) -> PartialResult {
+        if let Some(ranking) = &self.ranking {
+            let ranked = self
+                .ranked(&partial, ranking)
+                .into_iter()
+                .map(|(metric_id, _)| metric_id)
+                .collect::<HashSet<_>>();
+            partial.series.retain(|metric_id, _| ranked.contains(metric_id));
+        }
+        partial
+    }
+
+    /// Computes the final values of the query from the merged partial results of every shard
+    pub fn finish(&self, partial: PartialResult) -> QueryResult {
+        if let Some(ranking) = &self.ranking {
+            QueryResult::Ranking(self.ranked(&partial, ranking))
+        } else if self.selector == MetricSelector::Exact || self.combine {
+            QueryResult::Values(self.window_values(&partial.combined()))
+        } else {
+            let values = partial
+                .series
+                .iter()
+                .map(|(metric_id, windows)| (metric_id.clone(), self.window_values(windows)))
+                .collect();
+            QueryResult::PerMetric(values)
+        }
+    }
+
+    /// Metrics sorted by their aggregation over all their windows, keeping the first `limit`
+    fn ranked(&self, partial: &PartialResult, ranking: &Ranking) -> Vec<(String, f32)> {
+        let mut values = partial
+            .series
+            .iter()
+            .flat_map(|(metric_id, windows)| {
+                let mut total = AggregateState::default();
+                windows.values().for_each(|state| total.merge(state));
+                total
+                    .value(&self.aggregation)
+                    .map(|value| (metric_id.clone(), value))
+            })
+            .collect::<Vec<_>>();
+        values.sort_by(|(_, left), (_, right)| match ranking.order {
+            RankOrder::Top => right.total_cmp(left),
+            RankOrder::Bottom => left.total_cmp(right),
+        });
+        values.truncate(ranking.limit as usize);
+        values
+    }
+
+    fn window_values(&self, windows: &Windows) -> Vec<f32> {
+        windows
+            .values()
+            .flat_map(|state| state.value(&self.aggregation))
+            .collect()
+    }
+}
+
+/// Final result of a query
+#[derive(Debug)]
+pub enum QueryResult {
+    Values(Vec<f32>),
+    PerMetric(BTreeMap<String, Vec<f32>>),
+    /// Metrics with their values, in ranking order
+    Ranking(Vec<(String, f32)>),
+}
+
+impl QueryResult {
+    /// Number of values in the result
+    pub fn len(&self) -> usize {
+        match self {
+            QueryResult::Values(values) => values.len(),
+            QueryResult::PerMetric(values) => values.values().map(Vec::len).sum(),
+            QueryResult::Ranking(values) => values.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl fmt::Display for QueryResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryResult::Values(values) => write!(f, "{:?}", values),
+            QueryResult::PerMetric(values) => write!(f, "{:?}", values),
+            QueryResult::Ranking(values) => write!(f, "{:?}", values),
+        }
+    }
+}
+
+/// Checks whether a metric id is selected by a query
+pub enum MetricMatcher {
+    Exact(String),
+    Pattern(Regex),
+}
+
+impl MetricMatcher {
+    pub fn new(query: &QueryParams) -> io::Result<Self> {
+        let pattern = match query.selector {
+            MetricSelector::Exact => return Ok(MetricMatcher::Exact(query.metric_id.clone())),
+            MetricSelector::Wildcard => {
+                let parts = query
+                    .metric_id
+                    .split('*')
+                    .map(regex::escape)
+                    .collect::<Vec<_>>();
+                format!("^{}$", parts.join("[^.]*"))
+            }
+            MetricSelector::Regex => query.metric_id.clone(),
         };
-        result_vec
+        Regex::new(&pattern)
+            .map(MetricMatcher::Pattern)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn matches(&self, metric_id: &str) -> bool {
+        match self {
+            MetricMatcher::Exact(expected) => expected == metric_id,
+            MetricMatcher::Pattern(regex) => regex.is_match(metric_id),
+        }
     }
 }
 
-fn date_deserializer<'de, D>(deserializer: D) -> Result<Option<DateRange>, D::Error>
-    where
-        D: Deserializer<'de>,
-{
-    if let Ok((from_str, to_str)) = Deserialize::deserialize(deserializer) {
-        let from_naive = NaiveDateTime::parse_from_str(from_str, "%Y-%m-%d %H:%M:%S").unwrap();
-        let to_naive = NaiveDateTime::parse_from_str(to_str, "%Y-%m-%d %H:%M:%S").unwrap();
-        let range = (
-            DateTime::from_utc(from_naive, Utc),
-            DateTime::from_utc(to_naive, Utc),
-        );
-        Ok(Some(range))
-    } else {
-        Ok(None)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(metric_id: &str, selector: MetricSelector, aggregation: QueryAggregation) -> QueryParams {
+        QueryParams {
+            metric_id: metric_id.to_string(),
+            selector,
+            combine: false,
+            date_range: None,
+            aggregation,
+            window_secs: 0.0,
+            ranking: None,
+            timeout_ms: None,
+            partial: false,
+        }
+    }
+
+    fn handler(samples: &[(&str, usize)]) -> PartialResult {
+        let mut partial = PartialResult::default();
+        for (metric_id, count) in samples {
+            for _ in 0..*count {
+                partial.push(metric_id, 0, 1.0, false);
+            }
+        }
+        partial
+    }
+
+    #[test]
+    fn rankings_of_metrics_split_among_handlers_are_pruned_after_merging() {
+        let mut top = query("*", MetricSelector::Wildcard, QueryAggregation::Count);
+        top.ranking = Some(Ranking {
+            order: RankOrder::Top,
+            limit: 1,
+        });
+        // `a` is second in both handlers, but first once they are merged
+        let handlers = [handler(&[("a", 3), ("b", 4)]), handler(&[("a", 3), ("c", 4)])];
+        let mut merged = PartialResult::default();
+        handlers.iter().for_each(|partial| merged.merge(partial.clone()));
+        let QueryResult::Ranking(ranking) = top.finish(merged) else {
+            panic!("not a ranking");
+        };
+        assert_eq!(ranking, [("a".to_string(), 6.0)]);
+
+        let mut pruned = PartialResult::default();
+        handlers.iter().for_each(|partial| pruned.merge(top.retain_ranked(partial.clone())));
+        assert!(!pruned.series.contains_key("a"));
+    }
+
+    #[test]
+    fn bottom_rankings_keep_the_lowest_values() {
+        let mut bottom = query("*", MetricSelector::Wildcard, QueryAggregation::Count);
+        bottom.ranking = Some(Ranking {
+            order: RankOrder::Bottom,
+            limit: 2,
+        });
+        let partial = handler(&[("a", 3), ("b", 1), ("c", 2)]);
+        assert_eq!(bottom.retain_ranked(partial.clone()).series.keys().collect::<Vec<_>>(), ["b", "c"]);
+        let QueryResult::Ranking(ranking) = bottom.finish(partial) else {
+            panic!("not a ranking");
+        };
+        assert_eq!(ranking, [("b".to_string(), 1.0), ("c".to_string(), 2.0)]);
+    }
+
+    #[test]
+    fn patterns_return_a_series_per_metric_unless_combined() {
+        let mut count = query("a*", MetricSelector::Wildcard, QueryAggregation::Count);
+        let partial = handler(&[("a1", 3), ("a2", 1)]);
+        let QueryResult::PerMetric(values) = count.finish(partial.clone()) else {
+            panic!("not per metric");
+        };
+        assert_eq!(values, BTreeMap::from([("a1".to_string(), vec![3.0]), ("a2".to_string(), vec![1.0])]));
+        count.combine = true;
+        let QueryResult::Values(values) = count.finish(partial) else {
+            panic!("not combined");
+        };
+        assert_eq!(values, [4.0]);
+    }
+
+    #[test]
+    fn wildcards_stop_at_dots() {
+        let matcher = MetricMatcher::new(&query("api.*.latency", MetricSelector::Wildcard, QueryAggregation::Avg)).unwrap();
+        assert!(matcher.matches("api.users.latency"));
+        assert!(!matcher.matches("api.users.v2.latency"));
+        assert!(!matcher.matches("xapi.users.latency"));
+    }
+
+    #[test]
+    fn queries_round_trip_on_the_wire() {
+        let mut params = query("api.*", MetricSelector::Wildcard, QueryAggregation::Percentile(99.5));
+        params.ranking = Some(Ranking {
+            order: RankOrder::Bottom,
+            limit: 7,
+        });
+        params.timeout_ms = Some(250);
+        params.partial = true;
+        let mut bytes = vec![];
+        params.write_to(&mut bytes).unwrap();
+        let read = QueryParams::from_stream(bytes.as_slice()).unwrap();
+        assert_eq!(read.aggregation, QueryAggregation::Percentile(99.5));
+        assert_eq!(read.selector, MetricSelector::Wildcard);
+        assert_eq!(read.ranking.map(|ranking| (ranking.order, ranking.limit)), Some((RankOrder::Bottom, 7)));
+        assert_eq!((read.timeout_ms, read.partial), (Some(250), true));
     }
 }
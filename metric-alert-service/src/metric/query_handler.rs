@@ -1,28 +1,48 @@
-use crate::metric::{MetricIterator, Query, QueryParams};
-use chrono::{DateTime, Duration, DurationRound, Utc};
-use log::{info, warn};
-use std::collections::hash_map::DefaultHasher;
-use std::fs::File;
-use std::hash::{Hash, Hasher};
+use crate::metric::aggregate::PartialResult;
+use crate::metric::cache::SharedQueryCache;
+use crate::metric::query::{MetricMatcher, MetricSelector, QueryResult};
+use crate::metric::bloom::BloomFilter;
+use crate::metric::router::ShardRouter;
+use crate::metric::segment::{bloom_path, read_summary, Segment};
+use crate::metric::{DateRange, Query, QueryMessage, QueryParams, TEMP_FILE_LIFETIME};
+use chrono::Utc;
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, VecDeque};
 use std::io;
-use std::ops::Add;
-use crossbeam_channel::Receiver;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{bounded, unbounded as channel, Receiver, RecvTimeoutError, Sender};
 use threadpool::ThreadPool;
 
-const TEMP_FILE_LIFETIME: i64 = 5;
+/// How often a query waiting for results checks whether it should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Threads reading segments, shared by every handler. It is also the number of segments a
+/// query reads at the same time
+const SCAN_POOL_SIZE: usize = 8;
 
 pub struct QueryHandlerPool {
     pool: ThreadPool,
 }
 
 impl QueryHandlerPool {
-    pub fn new(receivers: Vec<Receiver<Query>>, metrics_root: String) -> Self {
-        let n_receivers = receivers.len();
-        let pool = ThreadPool::new(n_receivers);
-        for receiver in receivers.into_iter() {
+    /// Each handler gets the cache of its shard, `caches` has one for each receiver
+    pub fn new(
+        receivers: Vec<Receiver<Query>>,
+        metrics_root: String,
+        caches: Vec<SharedQueryCache>,
+        router: Arc<ShardRouter>,
+    ) -> Self {
+        let pool = ThreadPool::new(receivers.len());
+        let scan_pool = ThreadPool::new(SCAN_POOL_SIZE);
+        for (id, (receiver, cache)) in receivers.into_iter().zip(caches).enumerate() {
             let root_clone = metrics_root.clone();
+            let scan_pool_clone = scan_pool.clone();
+            let segment_shards = router.segment_shards(id);
+            let single_owner = router.single_owner();
             pool.execute(move || {
-                let mut handler = QueryHandler::new(n_receivers, root_clone);
+                let mut handler =
+                    QueryHandler::new(id, root_clone, scan_pool_clone, cache, segment_shards, single_owner);
                 handler.run(receiver).unwrap();
             });
         }
@@ -34,23 +54,277 @@ impl QueryHandlerPool {
     }
 }
 
+/// Sends a query to the handlers owning the selected metrics and merges their results.
+/// Exact metric ids are routed by the router, patterns are sent to every handler. The query is
+/// cancelled once `abandoned` returns true
+pub fn run_query(
+    router: &ShardRouter,
+    query_senders: &[Sender<Query>],
+    query_params: QueryParams,
+    abandoned: impl Fn() -> bool + 'static,
+) -> io::Result<QueryResult> {
+    let partial = dispatch_query(router, query_senders, &query_params)?
+        .cancel_when(abandoned)
+        .collect()?;
+    Ok(query_params.finish(partial))
+}
+
+/// Shared by the handlers running a query and whoever waits for its results, so handlers can
+/// stop once the deadline passes or nobody waits for the results anymore
+#[derive(Clone, Debug)]
+pub struct QueryControl {
+    deadline: Option<Instant>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl QueryControl {
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Time left until the deadline, `None` if the query has no deadline
+    fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+/// Sends a query to its handlers without waiting for them, returning the stream of their results
+pub fn dispatch_query(
+    router: &ShardRouter,
+    query_senders: &[Sender<Query>],
+    query_params: &QueryParams,
+) -> io::Result<ResultStream> {
+    let shards = if query_params.selector == MetricSelector::Exact {
+        router.query_shards(&query_params.metric_id)
+    } else {
+        (0..query_senders.len()).collect()
+    };
+    let mut query_params = query_params.clone();
+    query_params.resolve_time(Utc::now())?;
+    let control = QueryControl::new(query_params.timeout_ms.map(Duration::from_millis));
+    let (result_sender, result_recv) = channel();
+    for idx in &shards {
+        debug!("Querying {:?} in pipe {}", query_params, idx);
+        let query = (query_params.clone(), result_sender.clone(), control.clone());
+        query_senders[*idx].send(query).ok();
+    }
+    Ok(ResultStream {
+        result_recv,
+        watermarks: shards.into_iter().map(|idx| (idx, i64::MIN)).collect(),
+        window_length: query_params.window_length(),
+        pending: PartialResult::default(),
+        control,
+        partial: query_params.partial,
+        timed_out: false,
+        abandoned: None,
+        checked: Instant::now(),
+    })
+}
+
+/// Results of a query as they are sent by its handlers. Handlers go through their segments in
+/// chronological order, so a window is closed once every handler has moved past its end.
+/// Dropping the stream cancels the query
+pub struct ResultStream {
+    result_recv: Receiver<QueryMessage>,
+    /// Watermark of the handlers that are still running
+    watermarks: BTreeMap<usize, i64>,
+    window_length: Option<i64>,
+    pending: PartialResult,
+    control: QueryControl,
+    /// Whether the results computed before a timeout are returned instead of an error
+    partial: bool,
+    timed_out: bool,
+    /// Tells whether nobody waits for the results anymore
+    abandoned: Option<Box<dyn Fn() -> bool>>,
+    /// Last time `abandoned` was checked, it is checked once every poll interval
+    checked: Instant,
+}
+
+impl ResultStream {
+    /// Cancels the query as soon as `abandoned` returns true, checked while waiting for results
+    pub fn cancel_when(mut self, abandoned: impl Fn() -> bool + 'static) -> Self {
+        self.abandoned = Some(Box::new(abandoned));
+        self
+    }
+
+    /// Whether the query timed out, so the results only cover part of the segments
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    /// Waits until some windows are closed and returns them, or `None` when the query is done
+    pub fn next_closed(&mut self) -> Option<io::Result<PartialResult>> {
+        loop {
+            if self.watermarks.is_empty() {
+                if self.pending.series.is_empty() {
+                    return None;
+                }
+                return Some(Ok(std::mem::take(&mut self.pending)));
+            }
+            if let Some(window_length) = self.window_length {
+                let watermark = self.watermarks.values().min().copied().unwrap_or(i64::MAX);
+                let closed = self.pending.split_before(watermark.saturating_sub(window_length) + 1);
+                if !closed.series.is_empty() {
+                    return Some(Ok(closed));
+                }
+            }
+            if let Err(e) = self.receive() {
+                self.watermarks.clear();
+                self.pending = PartialResult::default();
+                return Some(Err(e));
+            }
+        }
+    }
+
+    /// Waits for every handler and merges their results
+    pub fn collect(&mut self) -> io::Result<PartialResult> {
+        while !self.watermarks.is_empty() {
+            self.receive()?;
+        }
+        Ok(std::mem::take(&mut self.pending))
+    }
+
+    fn receive(&mut self) -> io::Result<()> {
+        loop {
+            if self.checked.elapsed() >= POLL_INTERVAL {
+                self.checked = Instant::now();
+                if self.abandoned.as_ref().is_some_and(|abandoned| abandoned()) {
+                    return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "query abandoned"));
+                }
+            }
+            let wait = match self.control.remaining() {
+                Some(remaining) if remaining.is_zero() => return self.time_out(),
+                Some(remaining) => remaining.min(POLL_INTERVAL),
+                None => POLL_INTERVAL,
+            };
+            match self.result_recv.recv_timeout(wait) {
+                Ok(message) => return self.handle_message(message),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::other("query handlers stopped before finishing"))
+                }
+            }
+        }
+    }
+
+    fn handle_message(&mut self, message: QueryMessage) -> io::Result<()> {
+        match message {
+            QueryMessage::Chunk {
+                shard,
+                result,
+                watermark,
+            } => {
+                self.pending.merge(result);
+                self.watermarks.insert(shard, watermark);
+                Ok(())
+            }
+            QueryMessage::Done { shard } => {
+                self.watermarks.remove(&shard);
+                Ok(())
+            }
+            QueryMessage::Error { shard, message } => Err(io::Error::other(format!(
+                "query failed in handler {}: {}",
+                shard, message
+            ))),
+            QueryMessage::TimedOut { .. } => self.time_out(),
+        }
+    }
+
+    /// Stops every handler. Partial queries finish with the results received so far
+    fn time_out(&mut self) -> io::Result<()> {
+        self.control.cancel();
+        if !self.partial {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "query timed out"));
+        }
+        self.timed_out = true;
+        self.watermarks.clear();
+        Ok(())
+    }
+}
+
+impl Drop for ResultStream {
+    fn drop(&mut self) {
+        self.control.cancel();
+    }
+}
+
+/// Where the partial results of a query come from
+enum Source {
+    Segment(String),
+    Cache(PartialResult),
+}
+
 struct QueryHandler {
-    hash_modulus: usize,
-    metrics_root: String
+    id: usize,
+    metrics_root: String,
+    scan_pool: ThreadPool,
+    cache: SharedQueryCache,
+    /// Shards whose segments this handler reads, its own and those of retired shards
+    segment_shards: Vec<usize>,
+    /// Whether no other handler has samples of the metrics of this one, so its rankings can
+    /// be pruned before they are merged
+    single_owner: bool,
 }
 
 impl QueryHandler {
-    pub fn new(hash_modulus: usize, metrics_root: String) -> Self {
-        Self { hash_modulus, metrics_root }
+    pub fn new(
+        id: usize,
+        metrics_root: String,
+        scan_pool: ThreadPool,
+        cache: SharedQueryCache,
+        segment_shards: Vec<usize>,
+        single_owner: bool,
+    ) -> Self {
+        Self {
+            id,
+            metrics_root,
+            scan_pool,
+            cache,
+            segment_shards,
+            single_owner,
+        }
     }
 
     pub fn run(&mut self, receiver: Receiver<Query>) -> io::Result<()> {
         loop {
             match receiver.recv() {
-                Ok((query_params, result_sender)) => {
+                Ok((query_params, result_sender, control)) => {
+                    if control.is_cancelled() {
+                        debug!("Skipping cancelled query");
+                        continue;
+                    }
                     info!("Handling query");
-                    let result = self.handle_query(query_params)?;
-                    result_sender.send(result).ok();
+                    let message = match self.handle_query(query_params, &result_sender, &control) {
+                        Ok(()) => QueryMessage::Done { shard: self.id },
+                        Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                            debug!("Query timed out");
+                            QueryMessage::TimedOut { shard: self.id }
+                        }
+                        Err(e) => {
+                            warn!("Query failed: {}", e);
+                            QueryMessage::Error {
+                                shard: self.id,
+                                message: e.to_string(),
+                            }
+                        }
+                    };
+                    result_sender.send(message).ok();
                 }
                 Err(_) => {
                     warn!("Error while handling query! Are we shutting down?");
@@ -60,54 +334,196 @@ impl QueryHandler {
         }
     }
 
-    fn handle_query(&mut self, query: QueryParams) -> io::Result<Vec<f32>> {
-        let mut hasher = DefaultHasher::new();
-        query.metric_id.hash(&mut hasher);
-        let hash = hasher.finish() as usize;
-        let metric_hash = hash % self.hash_modulus;
-        // TODO: Remove duplicated code
-        let result = if let Some((date_begin, date_end)) = query.date_range {
-            let date_iter = date_range_iterator(date_begin, date_end);
-            let metric_iterator = date_iter
-                .map(|date| format!("{}/{}_{:x}.metric.tmp", self.metrics_root, metric_hash, date.timestamp()))
-                .flat_map(File::open)
-                .flat_map(MetricIterator::new);
-            query.process_metrics(metric_iterator)
-        } else {
-            let paths = std::fs::read_dir(&self.metrics_root).unwrap();
-            let metric_iterator = paths
-                .flatten()
-                .flat_map(|path| path.file_name().into_string())
-                .filter(|path| {
-                    let metric_hash_str = format!("{}", metric_hash);
-                    path.starts_with(&metric_hash_str)
-                })
-                .map(|path| format!("{}/{}", self.metrics_root, path))
-                .flat_map(File::open)
-                .flat_map(MetricIterator::new);
-            query.process_metrics(metric_iterator)
+    /// Reads several segments at the same time in the scan pool, and sends the partial result of
+    /// each one in chronological order as soon as it is processed. Rankings are sent at the end,
+    /// keeping only this handler's top metrics when no other handler has samples of them. The
+    /// control is checked before each segment, a query past its deadline stops with a
+    /// `TimedOut` error
+    fn handle_query(
+        &mut self,
+        query: QueryParams,
+        result_sender: &Sender<QueryMessage>,
+        control: &QueryControl,
+    ) -> io::Result<()> {
+        let query = Arc::new(query);
+        let matcher = Arc::new(MetricMatcher::new(&query)?);
+        let date_range = query.date_range.map(|range| range.resolve(Utc::now())).transpose()?;
+        // Only windowed queries with a range are cached
+        let cache_range = date_range
+            .filter(|_| query.window_length().is_some())
+            .map(|(from, to)| (from.timestamp(), to.timestamp()));
+        let (cached, published_until) = {
+            let mut cache = self.cache.lock().unwrap();
+            let cached = cache_range.and_then(|range| cache.get(&query, range));
+            (cached, cache.published_until())
         };
-        Ok(result)
+        let cached_span = cached.as_ref().map(|cached| (cached.from, cached.until));
+        let mut sources = self
+            .segments(date_range)?
+            .into_iter()
+            .filter(|(segment_start, _)| match cached_span {
+                // Segments fully inside the cached windows are not read
+                Some((from, until)) => *segment_start < from || segment_start + TEMP_FILE_LIFETIME > until,
+                None => true,
+            })
+            .map(|(segment_start, path)| {
+                (segment_start, segment_start + TEMP_FILE_LIFETIME, Source::Segment(path))
+            })
+            .collect::<Vec<_>>();
+        if let Some(cached) = cached {
+            sources.push((cached.from, cached.until, Source::Cache(cached.windows)));
+            sources.sort_by_key(|(start, _, _)| *start);
+        }
+        let mut sources = sources.into_iter();
+        let mut scans = VecDeque::with_capacity(SCAN_POOL_SIZE);
+        let mut pending = PartialResult::default();
+        let mut scanned = PartialResult::default();
+        let mut watermark = i64::MIN;
+        let mut timed_out = false;
+        loop {
+            if control.is_cancelled() {
+                debug!("Query cancelled");
+                return Ok(());
+            }
+            if control.is_expired() {
+                timed_out = true;
+                break;
+            }
+            while scans.len() < SCAN_POOL_SIZE {
+                match sources.next() {
+                    Some((_, source_watermark, source)) => {
+                        let scan = self.scan(source, &query, &matcher, date_range, cached_span);
+                        scans.push_back((source_watermark, scan));
+                    }
+                    None => break,
+                }
+            }
+            let Some((source_watermark, scan)) = scans.pop_front() else {
+                break;
+            };
+            let partial = scan
+                .recv()
+                .map_err(|_| io::Error::other("segment scan failed"))?;
+            if cache_range.is_some() {
+                scanned.merge(partial.clone());
+            }
+            pending.merge(partial);
+            watermark = watermark.max(source_watermark);
+            if query.ranking.is_none() {
+                let chunk = QueryMessage::Chunk {
+                    shard: self.id,
+                    result: std::mem::take(&mut pending),
+                    watermark,
+                };
+                if result_sender.send(chunk).is_err() {
+                    debug!("Query abandoned");
+                    return Ok(());
+                }
+            }
+        }
+        if query.ranking.is_some() {
+            let result = if self.single_owner {
+                query.retain_ranked(pending)
+            } else {
+                pending
+            };
+            let chunk = QueryMessage::Chunk {
+                shard: self.id,
+                result,
+                watermark: i64::MAX,
+            };
+            result_sender.send(chunk).ok();
+        }
+        if timed_out {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "query timed out"));
+        }
+        if let Some(range) = cache_range {
+            self.cache.lock().unwrap().put(&query, range, scanned, published_until);
+        }
+        Ok(())
+    }
+
+    /// Processes a segment in the scan pool, from its footer when possible, returning where its
+    /// partial result will be sent. Segments without the queried metric are skipped, and windows
+    /// in the cached span are left out, they come from the cache
+    fn scan(
+        &self,
+        source: Source,
+        query: &Arc<QueryParams>,
+        matcher: &Arc<MetricMatcher>,
+        date_range: Option<DateRange>,
+        cached_span: Option<(i64, i64)>,
+    ) -> Receiver<PartialResult> {
+        let (sender, receiver) = bounded(1);
+        let path = match source {
+            Source::Segment(path) => path,
+            Source::Cache(windows) => {
+                sender.send(windows).ok();
+                return receiver;
+            }
+        };
+        let query = query.clone();
+        let matcher = matcher.clone();
+        self.scan_pool.execute(move || {
+            if !may_contain(&path, &matcher) {
+                sender.send(PartialResult::default()).ok();
+                return;
+            }
+            // Segments may be removed while the query runs, missing ones are skipped
+            let summarized = read_summary(&path)
+                .ok()
+                .flatten()
+                .and_then(|summary| query.process_summary(&matcher, date_range, &summary));
+            let mut partial = match summarized {
+                Some(partial) => partial,
+                None => {
+                    let segment = Segment::open(&path).ok();
+                    let records = segment.iter().flat_map(Segment::records);
+                    query.process_metrics(&matcher, date_range, records)
+                }
+            };
+            if let Some((from, until)) = cached_span {
+                partial.remove_range(from, until);
+            }
+            sender.send(partial).ok();
+        });
+        receiver
+    }
+
+    /// Rotated segments of this handler overlapping the range, sorted by the time they start
+    fn segments(&self, date_range: Option<DateRange>) -> io::Result<Vec<(i64, String)>> {
+        let mut segments = std::fs::read_dir(&self.metrics_root)?
+            .collect::<io::Result<Vec<_>>>()?
+            .into_iter()
+            .flat_map(|path| path.file_name().into_string())
+            .filter_map(|path| {
+                let (shard, start) = path.strip_suffix(".metric.tmp")?.split_once('_')?;
+                if !self.segment_shards.contains(&shard.parse().ok()?) {
+                    return None;
+                }
+                let start = i64::from_str_radix(start, 16).ok()?;
+                Some((start, format!("{}/{}", self.metrics_root, path)))
+            })
+            .filter(|(start, _)| match date_range {
+                Some((from, to)) => {
+                    start + TEMP_FILE_LIFETIME > from.timestamp() && *start <= to.timestamp()
+                }
+                None => true,
+            })
+            .collect::<Vec<_>>();
+        segments.sort();
+        Ok(segments)
     }
 }
 
-fn date_range_iterator(
-    date_begin: DateTime<Utc>,
-    date_end: DateTime<Utc>,
-) -> impl Iterator<Item = DateTime<Utc>> {
-    let mut current_time_slice = date_begin
-        .duration_trunc(Duration::seconds(TEMP_FILE_LIFETIME))
-        .unwrap();
-    let end = date_end
-        .duration_trunc(Duration::seconds(TEMP_FILE_LIFETIME))
-        .unwrap();
-    std::iter::from_fn(move || {
-        if current_time_slice <= end {
-            let res = current_time_slice;
-            current_time_slice = current_time_slice.add(Duration::seconds(TEMP_FILE_LIFETIME));
-            Some(res)
-        } else {
-            None
-        }
-    })
+/// Checks the bloom filter of a segment before reading it. Only exact ids can be checked, and
+/// segments without a filter may contain anything
+fn may_contain(segment_path: &str, matcher: &MetricMatcher) -> bool {
+    let MetricMatcher::Exact(metric_id) = matcher else {
+        return true;
+    };
+    match BloomFilter::load(&bloom_path(segment_path)) {
+        Ok(Some(filter)) => filter.may_contain(metric_id),
+        _ => true,
+    }
 }
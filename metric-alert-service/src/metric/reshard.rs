@@ -0,0 +1,184 @@
+use crate::metric::bloom::BloomFilter;
+use crate::metric::router::{Manifest, ShardLayout, ShardRouter};
+use crate::metric::segment::{bloom_path, Segment, SegmentSummary};
+use log::info;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+
+/// Folder where the new segments are written before replacing the old ones
+const STAGING_FOLDER: &str = "reshard";
+/// Names of the old segments, written once every new segment is staged
+const SOURCES_FILE: &str = "sources";
+/// Written once the old segments are deleted, so a resumed run doesn't delete the new ones
+const DELETED_FILE: &str = "deleted";
+
+#[derive(Debug, Default)]
+pub struct ReshardStats {
+    pub segments_read: usize,
+    pub segments_written: usize,
+    pub samples: u64,
+}
+
+/// Rewrites the rotated segments of a stopped server with the current layout for `shards`,
+/// leaving a manifest with only that layout. Running it again resumes an interrupted run
+pub fn reshard(metrics_root: &str, shards: usize) -> io::Result<ReshardStats> {
+    if shards == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "At least one shard is needed"));
+    }
+    let layout = ShardLayout::current(shards);
+    let staging = format!("{}/{}", metrics_root, STAGING_FOLDER);
+    let sources_path = format!("{}/{}", staging, SOURCES_FILE);
+    let mut stats = ReshardStats::default();
+    if std::path::Path::new(&sources_path).exists() {
+        info!("Resuming the re-shard staged in {}", staging);
+    } else {
+        match std::fs::remove_dir_all(&staging) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => info!("Re-sharding {} with {:?}", metrics_root, layout),
+        }
+        let manifest = Manifest::load(metrics_root)?;
+        if manifest.is_some_and(|manifest| manifest.layouts == [layout]) {
+            info!("Segments already use {:?}", layout);
+            return Ok(stats);
+        }
+        std::fs::create_dir_all(&staging)?;
+        let router = ShardRouter::new(vec![layout]);
+        let segments = segments(metrics_root)?;
+        for (start, names) in &segments {
+            let mut outputs = BTreeMap::new();
+            for name in names {
+                let segment = Segment::open(&format!("{}/{}", metrics_root, name))?;
+                for record in segment.records() {
+                    let (records, summary) = outputs
+                        .entry(router.shard(record.metric_id))
+                        .or_insert_with(|| (vec![], SegmentSummary::default()));
+                    summary.record(record.metric_id, record.value, record.timestamp);
+                    record.write_to(records)?;
+                    stats.samples += 1;
+                }
+                stats.segments_read += 1;
+            }
+            for (shard, (records, summary)) in outputs {
+                let path = format!("{}/{}_{}.metric.tmp", staging, shard, start);
+                write_segment(&path, &records, &summary)?;
+                stats.segments_written += 1;
+            }
+        }
+        let tmp_path = format!("{}.tmp", sources_path);
+        let mut file = File::create(&tmp_path)?;
+        for name in segments.values().flatten() {
+            writeln!(file, "{}", name)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(tmp_path, &sources_path)?;
+    }
+    let deleted_path = format!("{}/{}", staging, DELETED_FILE);
+    if !std::path::Path::new(&deleted_path).exists() {
+        for name in BufReader::new(File::open(&sources_path)?).lines() {
+            let path = format!("{}/{}", metrics_root, name?);
+            for path in [bloom_path(&path), path] {
+                match std::fs::remove_file(path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+        }
+        File::create(&deleted_path)?;
+    }
+    for path in std::fs::read_dir(&staging)? {
+        let path = path?;
+        let name = path.file_name().to_string_lossy().to_string();
+        if name.ends_with(".metric.tmp") || name.ends_with(".bloom") {
+            std::fs::rename(path.path(), format!("{}/{}", metrics_root, name))?;
+        }
+    }
+    let manifest = Manifest {
+        format: Manifest::FORMAT,
+        layouts: vec![layout],
+    };
+    manifest.save(metrics_root)?;
+    std::fs::remove_dir_all(&staging)?;
+    Ok(stats)
+}
+
+/// Names of the rotated segments, grouped by the hex timestamp of their start
+fn segments(metrics_root: &str) -> io::Result<BTreeMap<String, Vec<String>>> {
+    let mut segments = BTreeMap::<_, Vec<_>>::new();
+    for path in std::fs::read_dir(metrics_root)? {
+        let path = path?;
+        let Ok(name) = path.file_name().into_string() else {
+            continue;
+        };
+        let Some((_, start)) = name
+            .strip_suffix(".metric.tmp")
+            .and_then(|stem| stem.split_once('_'))
+        else {
+            continue;
+        };
+        segments.entry(start.to_string()).or_default().push(name);
+    }
+    Ok(segments)
+}
+
+/// Writes a segment with its footer and bloom filter
+fn write_segment(path: &str, records: &[u8], summary: &SegmentSummary) -> io::Result<()> {
+    BloomFilter::from_ids(summary.metrics.keys()).save(&bloom_path(path))?;
+    let mut file = File::create(path)?;
+    file.write_all(records)?;
+    summary.write_footer(&mut file)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metric::segment::Record;
+    use crate::test_util::TempDir;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn resharding_writes_the_manifest_of_legacy_roots() {
+        let dir = TempDir::new("reshard-legacy");
+        let root = dir.path();
+        let metric_ids = (0..20).map(|i| format!("metric-{}", i)).collect::<Vec<_>>();
+        for shard in 0..2 {
+            let mut records = vec![];
+            let mut summary = SegmentSummary::default();
+            for metric_id in metric_ids.iter().skip(shard).step_by(2) {
+                let record = Record {
+                    metric_id,
+                    value: 1.0,
+                    timestamp: Utc.timestamp(1_650_000_000, 0),
+                };
+                record.write_to(&mut records).unwrap();
+                summary.record(metric_id, record.value, record.timestamp);
+            }
+            write_segment(&dir.join(&format!("{}_62760000.metric.tmp", shard)), &records, &summary).unwrap();
+        }
+        assert!(ShardRouter::open(root, 4, None).is_err());
+
+        let stats = reshard(root, 4).unwrap();
+        let router = ShardRouter::open(root, 4, None).unwrap();
+        let manifest = Manifest::load(root).unwrap().unwrap();
+        let mut moved = vec![];
+        for shard in 0..4 {
+            let Ok(segment) = Segment::open(&dir.join(&format!("{}_62760000.metric.tmp", shard))) else {
+                continue;
+            };
+            for record in segment.records() {
+                assert_eq!(router.shard(record.metric_id), shard);
+                moved.push(record.metric_id.to_string());
+            }
+        }
+        drop(dir);
+
+        assert_eq!(stats.samples, 20);
+        assert_eq!(manifest.layouts, [ShardLayout::current(4)]);
+        moved.sort();
+        let mut metric_ids = metric_ids;
+        metric_ids.sort();
+        assert_eq!(moved, metric_ids);
+    }
+}
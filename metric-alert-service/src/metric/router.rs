@@ -0,0 +1,393 @@
+use crate::metric::hash::HashFunction;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io;
+
+/// Points of each shard in the ring. More points spread the metrics more evenly
+const VIRTUAL_NODES: usize = 64;
+const MANIFEST_FILE: &str = "manifest.json";
+/// Shard map written before the manifest, all its layouts use the legacy hash
+const LEGACY_SHARD_MAP_FILE: &str = "shards.json";
+
+/// How metric ids are spread among a number of shards
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Placement {
+    /// Hash modulo the number of shards, used before shard maps existed
+    Modulo(usize),
+    /// Consistent hashing, so changing the number of shards only moves a few metrics
+    Ring(usize),
+}
+
+/// How metric ids were assigned to shards while the server ran with a configuration
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ShardLayout {
+    pub placement: Placement,
+    pub hash: HashFunction,
+}
+
+/// Description of the data in a metrics root. `layouts` holds every layout used to write the
+/// stored segments, oldest first, the last one being the current layout
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Manifest {
+    pub format: u32,
+    pub layouts: Vec<ShardLayout>,
+}
+
+/// Routes metric ids to shards. Queries also reach the owners in previous layouts, so older data
+/// stays reachable after the number of shards or the hash changes
+#[derive(Debug)]
+pub struct ShardRouter {
+    shards: usize,
+    /// Every layout with its ring, empty for modulo layouts
+    layouts: Vec<(ShardLayout, Vec<(u64, usize)>)>,
+}
+
+impl Placement {
+    fn shards(&self) -> usize {
+        match self {
+            Placement::Modulo(shards) | Placement::Ring(shards) => *shards,
+        }
+    }
+}
+
+impl ShardLayout {
+    /// Layout for new data with a number of shards
+    pub fn current(shards: usize) -> Self {
+        Self {
+            placement: Placement::Ring(shards),
+            hash: HashFunction::CURRENT,
+        }
+    }
+}
+
+impl Manifest {
+    /// Version of the manifest layout
+    pub const FORMAT: u32 = 1;
+
+    /// Loads the manifest of a metrics root, or the layouts of its legacy shard map. `None` for
+    /// roots written before both, see `legacy`
+    pub fn load(metrics_root: &str) -> io::Result<Option<Self>> {
+        match File::open(manifest_path(metrics_root)) {
+            Ok(file) => return Ok(Some(serde_json::from_reader(file)?)),
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            Err(_) => {}
+        }
+        #[derive(Deserialize)]
+        struct LegacyShardMap {
+            layouts: Vec<Placement>,
+        }
+        match File::open(format!("{}/{}", metrics_root, LEGACY_SHARD_MAP_FILE)) {
+            Ok(file) => {
+                let layouts = serde_json::from_reader::<_, LegacyShardMap>(file)?.layouts;
+                Ok(Some(Self::legacy_layouts(layouts)))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Manifest of a root written before shard maps, when metrics went to the writer with their
+    /// hash modulo the number of writers. That number wasn't recorded, so the operator must give
+    /// it, or re-shard the root instead
+    pub fn legacy(metrics_root: &str, legacy_shards: Option<usize>) -> io::Result<Self> {
+        let paths = match std::fs::read_dir(metrics_root) {
+            Ok(paths) => paths,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::legacy_layouts(vec![])),
+            Err(e) => return Err(e),
+        };
+        let segment_shards = paths
+            .collect::<io::Result<Vec<_>>>()?
+            .into_iter()
+            .flat_map(|path| path.file_name().into_string())
+            .filter(|name| name.ends_with(".metric.tmp"))
+            .filter_map(|name| name.split_once('_')?.0.parse::<usize>().ok())
+            .collect::<BTreeSet<_>>();
+        match (segment_shards.last(), legacy_shards) {
+            (None, _) => Ok(Self::legacy_layouts(vec![])),
+            (Some(_), None) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} has segments but no manifest, so the number of shards that wrote them is unknown. \
+                     Set LEGACY_SHARDS to it or run the reshard tool",
+                    metrics_root
+                ),
+            )),
+            (Some(shard), Some(legacy_shards)) if *shard >= legacy_shards => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} has segments of shard {}, so it wasn't written by {} shards. Run the reshard tool",
+                    metrics_root, shard, legacy_shards
+                ),
+            )),
+            (Some(_), Some(legacy_shards)) => Ok(Self::legacy_layouts(vec![Placement::Modulo(legacy_shards)])),
+        }
+    }
+
+    /// Layouts written before hashes were versioned
+    fn legacy_layouts(placements: Vec<Placement>) -> Self {
+        Self {
+            format: Self::FORMAT,
+            layouts: placements
+                .into_iter()
+                .map(|placement| ShardLayout {
+                    placement,
+                    hash: HashFunction::Legacy,
+                })
+                .collect(),
+        }
+    }
+
+    /// Replaces the manifest atomically
+    pub fn save(&self, metrics_root: &str) -> io::Result<()> {
+        std::fs::create_dir_all(metrics_root)?;
+        let path = manifest_path(metrics_root);
+        let tmp_path = format!("{}.tmp", path);
+        let file = File::create(&tmp_path)?;
+        serde_json::to_writer_pretty(&file, self)?;
+        file.sync_all()?;
+        std::fs::rename(tmp_path, path)?;
+        match std::fs::remove_file(format!("{}/{}", metrics_root, LEGACY_SHARD_MAP_FILE)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ShardRouter {
+    /// Loads the manifest of the metrics root, recording the current layout in it. Roots without
+    /// one need the number of shards that wrote them, see `Manifest::legacy`
+    pub fn open(metrics_root: &str, shards: usize, legacy_shards: Option<usize>) -> io::Result<Self> {
+        let mut manifest = match Manifest::load(metrics_root)? {
+            Some(manifest) => manifest,
+            None => Manifest::legacy(metrics_root, legacy_shards)?,
+        };
+        let layout = ShardLayout::current(shards);
+        if manifest.layouts.last() != Some(&layout) {
+            info!("Using {:?}, previous layouts: {:?}", layout, manifest.layouts);
+            manifest.layouts.push(layout);
+            manifest.save(metrics_root)?;
+        }
+        Ok(Self::new(manifest.layouts))
+    }
+
+    /// Router for a list of layouts, the last one being the current
+    pub fn new(layouts: Vec<ShardLayout>) -> Self {
+        let shards = layouts.last().map_or(1, |layout| layout.placement.shards());
+        let layouts = layouts
+            .into_iter()
+            .map(|layout| (layout, ring(&layout)))
+            .collect();
+        Self { shards, layouts }
+    }
+
+    /// Shard where new samples of a metric are written
+    pub fn shard(&self, metric_id: &str) -> usize {
+        match self.layouts.last() {
+            Some((layout, ring)) => owner(layout, ring, metric_id),
+            None => 0,
+        }
+    }
+
+    /// Handlers with samples of a metric, from the current and previous layouts
+    pub fn query_shards(&self, metric_id: &str) -> Vec<usize> {
+        self.layouts
+            .iter()
+            .map(|(layout, ring)| owner(layout, ring, metric_id) % self.shards)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// Whether the samples of every metric are in the segments of a single handler, which
+    /// only holds while the data was written with a single layout
+    pub fn single_owner(&self) -> bool {
+        self.layouts.len() <= 1
+    }
+
+    /// Ids of the shards whose segments are read by a handler
+    pub fn segment_shards(&self, handler: usize) -> Vec<usize> {
+        let max_shards = self
+            .layouts
+            .iter()
+            .map(|(layout, _)| layout.placement.shards())
+            .max()
+            .unwrap_or(self.shards);
+        (handler..max_shards).step_by(self.shards).collect()
+    }
+}
+
+fn manifest_path(metrics_root: &str) -> String {
+    format!("{}/{}", metrics_root, MANIFEST_FILE)
+}
+
+fn owner(layout: &ShardLayout, ring: &[(u64, usize)], metric_id: &str) -> usize {
+    let hash = layout.hash.hash(metric_id);
+    match layout.placement {
+        Placement::Modulo(shards) => hash as usize % shards,
+        Placement::Ring(_) => {
+            // First point at or after the hash, wrapping around the ring
+            let idx = ring.partition_point(|(point, _)| *point < hash);
+            ring[idx % ring.len()].1
+        }
+    }
+}
+
+fn ring(layout: &ShardLayout) -> Vec<(u64, usize)> {
+    let Placement::Ring(shards) = layout.placement else {
+        return vec![];
+    };
+    let mut ring = (0..shards.max(1))
+        .flat_map(|shard| {
+            (0..VIRTUAL_NODES).map(move |node| {
+                let point = match layout.hash {
+                    HashFunction::Legacy => {
+                        let mut hasher = DefaultHasher::new();
+                        (shard, node).hash(&mut hasher);
+                        hasher.finish()
+                    }
+                    hash => hash.hash(&format!("{}#{}", shard, node)),
+                };
+                (point, shard)
+            })
+        })
+        .collect::<Vec<_>>();
+    ring.sort();
+    ring
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn layout(placement: Placement, hash: HashFunction) -> ShardLayout {
+        ShardLayout { placement, hash }
+    }
+
+    fn metric_ids() -> impl Iterator<Item = String> {
+        (0..2000).map(|idx| format!("host{}.cpu", idx))
+    }
+
+    /// Metrics root with empty segments, removed when dropped
+    fn metrics_root(name: &str, segments: &[&str]) -> TempDir {
+        let root = TempDir::new(&format!("router-{}", name));
+        for segment in segments {
+            File::create(root.join(segment)).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn queries_reach_the_owners_of_every_layout() {
+        let router = ShardRouter::new(vec![
+            layout(Placement::Modulo(4), HashFunction::Legacy),
+            ShardLayout::current(4),
+        ]);
+        let legacy = ShardRouter::new(vec![layout(Placement::Modulo(4), HashFunction::Legacy)]);
+        let current = ShardRouter::new(vec![ShardLayout::current(4)]);
+        assert!(!router.single_owner());
+        assert!(current.single_owner());
+        for metric_id in metric_ids() {
+            assert_eq!(router.shard(&metric_id), current.shard(&metric_id));
+            let shards = router.query_shards(&metric_id);
+            assert!(shards.contains(&legacy.shard(&metric_id)));
+            assert!(shards.contains(&current.shard(&metric_id)));
+            assert_eq!(current.query_shards(&metric_id), [current.shard(&metric_id)]);
+        }
+    }
+
+    #[test]
+    fn retired_shards_are_read_by_the_remaining_handlers() {
+        let router = ShardRouter::new(vec![ShardLayout::current(8), ShardLayout::current(3)]);
+        assert_eq!(router.segment_shards(0), [0, 3, 6]);
+        assert_eq!(router.segment_shards(1), [1, 4, 7]);
+        assert_eq!(router.segment_shards(2), [2, 5]);
+        let previous = ShardRouter::new(vec![ShardLayout::current(8)]);
+        for metric_id in metric_ids() {
+            let handler = previous.shard(&metric_id) % 3;
+            assert!(router.query_shards(&metric_id).contains(&handler));
+            assert!(router.query_shards(&metric_id).iter().all(|shard| *shard < 3));
+        }
+    }
+
+    #[test]
+    fn adding_a_shard_to_a_ring_moves_few_metrics() {
+        let four = ShardRouter::new(vec![ShardLayout::current(4)]);
+        let five = ShardRouter::new(vec![ShardLayout::current(5)]);
+        let moved = metric_ids()
+            .filter(|metric_id| four.shard(metric_id) != five.shard(metric_id))
+            .count();
+        // A fifth of the metrics should move to the new shard, and only to it
+        assert!(moved < 2000 * 3 / 10, "{} metrics moved", moved);
+        assert!(metric_ids()
+            .filter(|metric_id| four.shard(metric_id) != five.shard(metric_id))
+            .all(|metric_id| five.shard(&metric_id) == 4));
+    }
+
+    #[test]
+    fn opening_records_new_layouts_in_the_manifest() {
+        let root = metrics_root("manifest", &[]);
+        ShardRouter::open(root.path(), 4, None).unwrap();
+        ShardRouter::open(root.path(), 4, None).unwrap();
+        assert_eq!(Manifest::load(root.path()).unwrap().unwrap().layouts, [ShardLayout::current(4)]);
+        let router = ShardRouter::open(root.path(), 2, None).unwrap();
+        assert!(!router.single_owner());
+        assert_eq!(
+            Manifest::load(root.path()).unwrap().unwrap().layouts,
+            [ShardLayout::current(4), ShardLayout::current(2)]
+        );
+    }
+
+    #[test]
+    fn legacy_roots_use_the_given_number_of_shards() {
+        let root = metrics_root("legacy", &["0_62760000.metric.tmp", "1_62760000.metric.tmp"]);
+        assert!(Manifest::load(root.path()).unwrap().is_none());
+        ShardRouter::open(root.path(), 4, Some(2)).unwrap();
+        assert_eq!(
+            Manifest::load(root.path()).unwrap().unwrap().layouts,
+            [layout(Placement::Modulo(2), HashFunction::Legacy), ShardLayout::current(4)]
+        );
+    }
+
+    #[test]
+    fn legacy_roots_need_the_number_of_shards() {
+        let root = metrics_root("legacy-unknown", &["0_62760000.metric.tmp", "1_62760000.metric.tmp"]);
+        let e = ShardRouter::open(root.path(), 4, None).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("LEGACY_SHARDS"));
+        assert!(Manifest::load(root.path()).unwrap().is_none());
+    }
+
+    #[test]
+    fn legacy_roots_with_more_shards_must_be_resharded() {
+        let root = metrics_root("legacy-more", &["0_62760000.metric.tmp", "5_62760000.metric.tmp"]);
+        let e = ShardRouter::open(root.path(), 4, Some(4)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("reshard"));
+        assert!(Manifest::load(root.path()).unwrap().is_none());
+    }
+
+    #[test]
+    fn legacy_shard_maps_use_the_legacy_hash() {
+        let root = metrics_root("shard-map", &[]);
+        std::fs::write(
+            root.join(LEGACY_SHARD_MAP_FILE),
+            r#"{"layouts": [{"Modulo": 2}, {"Ring": 4}]}"#,
+        )
+        .unwrap();
+        ShardRouter::open(root.path(), 4, None).unwrap();
+        assert_eq!(
+            Manifest::load(root.path()).unwrap().unwrap().layouts,
+            [
+                layout(Placement::Modulo(2), HashFunction::Legacy),
+                layout(Placement::Ring(4), HashFunction::Legacy),
+                ShardLayout::current(4)
+            ]
+        );
+        assert!(!std::path::Path::new(&root.join(LEGACY_SHARD_MAP_FILE)).exists());
+    }
+}
@@ -0,0 +1,302 @@
+use crate::metric::aggregate::AggregateState;
+use crate::metric::time::from_timestamp;
+use crate::metric::{read_string, write_string};
+use chrono::{DateTime, Utc};
+use memmap2::Mmap;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Marks the end of a segment with a footer. Segments written before footers existed end with
+/// a record instead
+const FOOTER_MAGIC: &[u8; 8] = b"MSUMMRY1";
+/// The footer is followed by its length as a big endian u32 and the magic
+const TRAILER_LEN: usize = 4 + FOOTER_MAGIC.len();
+
+/// Aggregation of every sample of a metric in a segment
+#[derive(Clone, Debug)]
+pub struct MetricSummary {
+    pub state: AggregateState,
+    pub first: DateTime<Utc>,
+    pub last: DateTime<Utc>,
+}
+
+/// Footer of a rotated segment, summarizing the samples of each metric it holds
+#[derive(Clone, Debug, Default)]
+pub struct SegmentSummary {
+    pub metrics: BTreeMap<String, MetricSummary>,
+}
+
+impl SegmentSummary {
+    pub fn record(&mut self, metric_id: &str, value: f32, timestamp: DateTime<Utc>) {
+        match self.metrics.get_mut(metric_id) {
+            Some(summary) => {
+                summary.state.push(value);
+                summary.first = summary.first.min(timestamp);
+                summary.last = summary.last.max(timestamp);
+            }
+            None => {
+                let mut state = AggregateState::default();
+                state.push(value);
+                let summary = MetricSummary {
+                    state,
+                    first: timestamp,
+                    last: timestamp,
+                };
+                self.metrics.insert(metric_id.to_string(), summary);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.metrics.clear();
+    }
+
+    fn from_stream<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let mut count_buf = [0; 4];
+        stream.read_exact(&mut count_buf)?;
+        let mut summary = Self::default();
+        for _ in 0..u32::from_be_bytes(count_buf) {
+            let metric_id = read_string(stream)?;
+            let mut buf = [0; 8];
+            let mut value_buf = [0; 4];
+            stream.read_exact(&mut buf)?;
+            let count = u64::from_be_bytes(buf);
+            stream.read_exact(&mut buf)?;
+            let sum = f64::from_be_bytes(buf);
+            stream.read_exact(&mut value_buf)?;
+            let min = f32::from_be_bytes(value_buf);
+            stream.read_exact(&mut value_buf)?;
+            let max = f32::from_be_bytes(value_buf);
+            stream.read_exact(&mut buf)?;
+            let first = from_timestamp(i64::from_be_bytes(buf))?;
+            stream.read_exact(&mut buf)?;
+            let last = from_timestamp(i64::from_be_bytes(buf))?;
+            let state = AggregateState {
+                count,
+                sum,
+                min,
+                max,
+                ..AggregateState::default()
+            };
+            summary
+                .metrics
+                .insert(metric_id, MetricSummary { state, first, last });
+        }
+        Ok(summary)
+    }
+
+    /// Appends the summary to the end of a segment
+    pub fn write_footer<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        let mut footer = vec![];
+        footer.write_all(&(self.metrics.len() as u32).to_be_bytes())?;
+        for (metric_id, summary) in &self.metrics {
+            write_string(&mut footer, metric_id)?;
+            footer.write_all(&summary.state.count.to_be_bytes())?;
+            footer.write_all(&summary.state.sum.to_be_bytes())?;
+            footer.write_all(&summary.state.min.to_be_bytes())?;
+            footer.write_all(&summary.state.max.to_be_bytes())?;
+            footer.write_all(&summary.first.timestamp().to_be_bytes())?;
+            footer.write_all(&summary.last.timestamp().to_be_bytes())?;
+        }
+        footer.write_all(&(footer.len() as u32).to_be_bytes())?;
+        footer.write_all(FOOTER_MAGIC)?;
+        stream.write_all(&footer)
+    }
+}
+
+/// Reads the footer of a segment without reading its records, `None` for segments without one
+pub fn read_summary(path: &str) -> io::Result<Option<SegmentSummary>> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    if size < TRAILER_LEN as u64 {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+    let mut trailer = [0; TRAILER_LEN];
+    file.read_exact(&mut trailer)?;
+    let Some(footer_len) = footer_len(&trailer) else {
+        return Ok(None);
+    };
+    if footer_len + TRAILER_LEN as u64 > size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid segment footer"));
+    }
+    file.seek(SeekFrom::End(-((footer_len + TRAILER_LEN as u64) as i64)))?;
+    let mut footer = vec![0; footer_len as usize];
+    file.read_exact(&mut footer)?;
+    SegmentSummary::from_stream(&mut footer.as_slice()).map(Some)
+}
+
+/// A rotated segment mapped in memory, whose records are decoded without copying them. Rotated
+/// segments are never written again, only removed, and a removed segment stays mapped until
+/// it is dropped
+pub struct Segment {
+    /// Empty segments can't be mapped
+    map: Option<Mmap>,
+    /// Length of the records, before the footer
+    records_len: usize,
+}
+
+/// A sample of a segment, borrowing its metric id from the segment
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Record<'a> {
+    pub metric_id: &'a str,
+    pub value: f32,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Records of a segment, up to the first one that can't be decoded
+pub struct Records<'a> {
+    data: &'a [u8],
+}
+
+impl Segment {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = File::open(path)?;
+        if file.metadata()?.len() == 0 {
+            return Ok(Self {
+                map: None,
+                records_len: 0,
+            });
+        }
+        // SAFETY: rotated segments are never modified, see above
+        let map = unsafe { Mmap::map(&file)? };
+        let mut records_len = map.len();
+        if map.len() >= TRAILER_LEN {
+            let trailer_start = map.len() - TRAILER_LEN;
+            if let Some(footer_len) = footer_len(&map[trailer_start..]) {
+                records_len = trailer_start.saturating_sub(footer_len as usize);
+            }
+        }
+        Ok(Self {
+            map: Some(map),
+            records_len,
+        })
+    }
+
+    pub fn records(&self) -> Records<'_> {
+        let data = self.map.as_ref().map_or(&[][..], |map| &map[..self.records_len]);
+        Records { data }
+    }
+}
+
+impl<'a> Record<'a> {
+    /// Writes the record as the writer does
+    pub fn write_to<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        write_string(stream, self.metric_id)?;
+        stream.write_all(&self.value.to_be_bytes())?;
+        stream.write_all(&self.timestamp.timestamp().to_be_bytes())
+    }
+}
+
+impl<'a> Records<'a> {
+    fn decode(&mut self) -> Option<Record<'a>> {
+        let id_len = u32::from_be_bytes(self.take()?) as usize;
+        let (metric_id, rest) = self.data.split_at_checked(id_len)?;
+        self.data = rest;
+        let metric_id = std::str::from_utf8(metric_id).ok()?;
+        let value = f32::from_be_bytes(self.take()?);
+        let timestamp = from_timestamp(i64::from_be_bytes(self.take()?)).ok()?;
+        Some(Record {
+            metric_id,
+            value,
+            timestamp,
+        })
+    }
+
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (bytes, rest) = self.data.split_first_chunk::<N>()?;
+        self.data = rest;
+        Some(*bytes)
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Record<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.decode();
+        if record.is_none() {
+            self.data = &[];
+        }
+        record
+    }
+}
+
+/// Path of the bloom filter of the metric ids in a segment
+pub fn bloom_path(segment_path: &str) -> String {
+    let stem = segment_path.strip_suffix(".metric.tmp").unwrap_or(segment_path);
+    format!("{}.bloom", stem)
+}
+
+/// Length of the footer described by a trailer, `None` if it isn't a footer trailer
+fn footer_len(trailer: &[u8]) -> Option<u64> {
+    if &trailer[4..] != FOOTER_MAGIC {
+        return None;
+    }
+    Some(u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_path;
+    use chrono::TimeZone;
+
+    fn record(metric_id: &str, value: f32, secs: i64) -> Record<'_> {
+        Record {
+            metric_id,
+            value,
+            timestamp: Utc.timestamp(secs, 0),
+        }
+    }
+
+    fn write(name: &str, data: &[u8]) -> String {
+        let path = temp_path(&format!("segment-{}", name));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn reads_the_records_before_the_footer() {
+        let records = [record("a", 1.5, 100), record("b", -2.0, 101), record("a", 3.0, 104)];
+        let mut data = vec![];
+        let mut summary = SegmentSummary::default();
+        for record in &records {
+            record.write_to(&mut data).unwrap();
+            summary.record(record.metric_id, record.value, record.timestamp);
+        }
+        summary.write_footer(&mut data).unwrap();
+        let path = write("footer", &data);
+        let segment = Segment::open(&path).unwrap();
+        let read_summary = read_summary(&path).unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(segment.records().collect::<Vec<_>>(), records);
+        let a = &read_summary.metrics["a"];
+        assert_eq!((a.state.count, a.state.sum, a.first, a.last), (2, 4.5, records[0].timestamp, records[2].timestamp));
+    }
+
+    #[test]
+    fn stops_at_a_truncated_record() {
+        let mut data = vec![];
+        record("a", 1.0, 100).write_to(&mut data).unwrap();
+        record("b", 2.0, 101).write_to(&mut data).unwrap();
+        data.truncate(data.len() - 3);
+        let path = write("truncated", &data);
+        let segment = Segment::open(&path).unwrap();
+        assert!(read_summary(&path).unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(segment.records().collect::<Vec<_>>(), [record("a", 1.0, 100)]);
+    }
+
+    #[test]
+    fn empty_segments_have_no_records() {
+        let path = write("empty", &[]);
+        let segment = Segment::open(&path).unwrap();
+        assert!(read_summary(&path).unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(segment.records().count(), 0);
+    }
+}
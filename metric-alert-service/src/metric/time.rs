@@ -0,0 +1,328 @@
+use crate::metric::DateRange;
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+/// Formats without time zone accepted for backwards compatibility, interpreted as UTC
+const NAIVE_FORMATS: [&str; 2] = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S"];
+/// Longest duration and relative offset accepted, about a century
+pub const MAX_DURATION_SECS: i64 = 100 * 365 * 86400;
+
+/// Point in time of a query range, either absolute or relative to the moment the server runs it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimePoint {
+    Absolute(DateTime<Utc>),
+    /// Offset in seconds from the time the query is executed
+    Relative(i64),
+}
+
+/// Range of a query. In JSON it is either a pair `["now-1h", "now"]` or a single string like
+/// `"now-1h..now"`, where a string without `..` is a range ending now
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeRange(pub TimePoint, pub TimePoint);
+
+impl TimePoint {
+    pub fn resolve(&self, now: DateTime<Utc>) -> io::Result<DateTime<Utc>> {
+        match self {
+            TimePoint::Absolute(time) => Ok(*time),
+            TimePoint::Relative(offset) => offset
+                .checked_mul(1000)
+                .and_then(|millis| now.checked_add_signed(Duration::milliseconds(millis)))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Time out of range")),
+        }
+    }
+
+    pub fn from_stream<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let mut code = [0];
+        stream.read_exact(&mut code)?;
+        let mut value_buf = [0; 8];
+        stream.read_exact(&mut value_buf)?;
+        let value = i64::from_be_bytes(value_buf);
+        match code[0] {
+            b'a' => Ok(TimePoint::Absolute(from_timestamp(value)?)),
+            b'r' if value.abs() <= MAX_DURATION_SECS => Ok(TimePoint::Relative(value)),
+            b'r' => Err(io::Error::new(io::ErrorKind::InvalidData, "Time offset out of range")),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid time point")),
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        match self {
+            TimePoint::Absolute(time) => {
+                stream.write_all(b"a")?;
+                stream.write_all(&time.timestamp().to_be_bytes())
+            }
+            TimePoint::Relative(offset) => {
+                stream.write_all(b"r")?;
+                stream.write_all(&offset.to_be_bytes())
+            }
+        }
+    }
+}
+
+impl TimeRange {
+    pub fn absolute((from, to): DateRange) -> Self {
+        TimeRange(TimePoint::Absolute(from), TimePoint::Absolute(to))
+    }
+
+    /// Range from `duration` ago until now
+    pub fn last(duration: Duration) -> Self {
+        TimeRange(
+            TimePoint::Relative(-duration.num_seconds()),
+            TimePoint::Relative(0),
+        )
+    }
+
+    pub fn resolve(&self, now: DateTime<Utc>) -> io::Result<DateRange> {
+        Ok((self.0.resolve(now)?, self.1.resolve(now)?))
+    }
+
+    /// Same range with relative points replaced by absolute ones
+    pub fn resolved(&self, now: DateTime<Utc>) -> io::Result<Self> {
+        Ok(TimeRange::absolute(self.resolve(now)?))
+    }
+}
+
+impl FromStr for TimePoint {
+    type Err = String;
+
+    /// Parses `now`, `now-5m`, RFC 3339 dates or dates without time zone in UTC
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+        if let Some(offset) = text.strip_prefix("now") {
+            let offset = offset.trim();
+            return if offset.is_empty() {
+                Ok(TimePoint::Relative(0))
+            } else if let Some(duration) = offset.strip_prefix('-') {
+                Ok(TimePoint::Relative(-parse_duration(duration.trim())? as i64))
+            } else if let Some(duration) = offset.strip_prefix('+') {
+                Ok(TimePoint::Relative(parse_duration(duration.trim())? as i64))
+            } else {
+                Err(format!("invalid offset '{}', expected something like now-5m", offset))
+            };
+        }
+        if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+            return Ok(TimePoint::Absolute(time.with_timezone(&Utc)));
+        }
+        NAIVE_FORMATS
+            .iter()
+            .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+            .map(|naive| TimePoint::Absolute(DateTime::from_utc(naive, Utc)))
+            .ok_or_else(|| {
+                format!(
+                    "invalid time '{}', expected 'now', 'now-<duration>' or a RFC 3339 date",
+                    text
+                )
+            })
+    }
+}
+
+impl FromStr for TimeRange {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.split_once("..") {
+            Some((from, to)) => Ok(TimeRange(from.parse()?, to.parse()?)),
+            None => Ok(TimeRange(text.parse()?, TimePoint::Relative(0))),
+        }
+    }
+}
+
+impl fmt::Display for TimePoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimePoint::Absolute(time) => write!(f, "{}", time.to_rfc3339()),
+            TimePoint::Relative(0) => write!(f, "now"),
+            TimePoint::Relative(offset) if *offset < 0 => write!(f, "now-{}s", -offset),
+            TimePoint::Relative(offset) => write!(f, "now+{}s", offset),
+        }
+    }
+}
+
+impl Serialize for TimePoint {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TimePoint {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(D::Error::custom)
+    }
+}
+
+impl Serialize for TimeRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.0, self.1).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum RangeRepr {
+            Pair(String, String),
+            Text(String),
+        }
+        let range = match RangeRepr::deserialize(deserializer)? {
+            RangeRepr::Pair(from, to) => TimeRange(
+                from.parse().map_err(D::Error::custom)?,
+                to.parse().map_err(D::Error::custom)?,
+            ),
+            RangeRepr::Text(text) => text.parse().map_err(D::Error::custom)?,
+        };
+        Ok(range)
+    }
+}
+
+/// Parses durations like `10s`, `5m`, `1h` or `1d` into seconds. A number without unit is in
+/// seconds, and durations are at most `MAX_DURATION_SECS`
+pub fn parse_duration(text: &str) -> Result<f32, String> {
+    let (number, multiplier) = match text.chars().last() {
+        Some('s') => (&text[..text.len() - 1], 1.0),
+        Some('m') => (&text[..text.len() - 1], 60.0),
+        Some('h') => (&text[..text.len() - 1], 3600.0),
+        Some('d') => (&text[..text.len() - 1], 86400.0),
+        _ => (text, 1.0),
+    };
+    match number.parse::<f32>().map(|value| value * multiplier) {
+        Ok(secs) if secs > MAX_DURATION_SECS as f32 => Err(format!("the duration '{}' is too long", text)),
+        Ok(secs) if secs >= 0.0 => Ok(secs),
+        _ => Err(format!("invalid duration '{}'", text)),
+    }
+}
+
+pub(crate) fn from_timestamp(timestamp: i64) -> io::Result<DateTime<Utc>> {
+    NaiveDateTime::from_timestamp_opt(timestamp, 0)
+        .map(|naive| DateTime::from_utc(naive, Utc))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Timestamp out of range"))
+}
+
+/// Reads an optional range. Ranges with only absolute points keep the original encoding
+pub(crate) fn read_time_range<R: Read>(stream: &mut R) -> io::Result<Option<TimeRange>> {
+    let mut has_time_range = [0];
+    stream.read_exact(&mut has_time_range)?;
+    match has_time_range[0] {
+        b'Y' => {
+            let mut timestamp_buf = [0; 8];
+            stream.read_exact(timestamp_buf.as_mut_slice())?;
+            let from = from_timestamp(i64::from_be_bytes(timestamp_buf))?;
+            stream.read_exact(timestamp_buf.as_mut_slice())?;
+            let to = from_timestamp(i64::from_be_bytes(timestamp_buf))?;
+            Ok(Some(TimeRange::absolute((from, to))))
+        }
+        b'R' => {
+            let from = TimePoint::from_stream(stream)?;
+            let to = TimePoint::from_stream(stream)?;
+            Ok(Some(TimeRange(from, to)))
+        }
+        b'N' => Ok(None),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid code")),
+    }
+}
+
+pub(crate) fn write_time_range<W: Write>(
+    stream: &mut W,
+    time_range: &Option<TimeRange>,
+) -> io::Result<()> {
+    match time_range {
+        Some(TimeRange(TimePoint::Absolute(from), TimePoint::Absolute(to))) => {
+            stream.write_all(b"Y")?;
+            stream.write_all(&from.timestamp().to_be_bytes())?;
+            stream.write_all(&to.timestamp().to_be_bytes())?;
+        }
+        Some(TimeRange(from, to)) => {
+            stream.write_all(b"R")?;
+            from.write_to(stream)?;
+            to.write_to(stream)?;
+        }
+        None => stream.write_all(b"N")?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn encoded(code: u8, value: i64) -> Vec<u8> {
+        let mut bytes = vec![code];
+        bytes.extend_from_slice(&value.to_be_bytes());
+        bytes
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("10"), Ok(10.0));
+        assert_eq!(parse_duration("10s"), Ok(10.0));
+        assert_eq!(parse_duration("1.5m"), Ok(90.0));
+        assert_eq!(parse_duration("2h"), Ok(7200.0));
+        assert_eq!(parse_duration("1d"), Ok(86400.0));
+        for invalid in ["", "s", "-5s", "tens", "infs", "NaNs", "1e20s", "200000d"] {
+            assert!(parse_duration(invalid).is_err(), "{:?} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn parses_time_points() {
+        let time = Utc.ymd(2022, 5, 7).and_hms(21, 18, 0);
+        assert_eq!("now".parse(), Ok(TimePoint::Relative(0)));
+        assert_eq!("now-5m".parse(), Ok(TimePoint::Relative(-300)));
+        assert_eq!("now + 1h".parse(), Ok(TimePoint::Relative(3600)));
+        assert_eq!("2022-05-07T21:18:00Z".parse(), Ok(TimePoint::Absolute(time)));
+        assert_eq!("2022-05-07T23:18:00+02:00".parse(), Ok(TimePoint::Absolute(time)));
+        assert_eq!("2022-05-07 21:18:00".parse(), Ok(TimePoint::Absolute(time)));
+        for invalid in ["now*5m", "now-infs", "now-1e20s", "yesterday", "2022-13-01T00:00:00Z"] {
+            assert!(invalid.parse::<TimePoint>().is_err(), "{:?} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn parses_ranges() {
+        let last_hour = TimeRange(TimePoint::Relative(-3600), TimePoint::Relative(0));
+        assert_eq!("now-1h..now".parse(), Ok(last_hour));
+        assert_eq!("now-1h".parse(), Ok(last_hour));
+        assert_eq!(serde_json::from_str::<TimeRange>(r#"["now-1h", "now"]"#).unwrap(), last_hour);
+        assert_eq!(serde_json::from_str::<TimeRange>(r#""now-1h..now""#).unwrap(), last_hour);
+    }
+
+    #[test]
+    fn resolves_relative_points() {
+        let now = Utc.ymd(2022, 5, 7).and_hms(21, 18, 0);
+        let range = TimeRange(TimePoint::Relative(-60), TimePoint::Relative(0));
+        assert_eq!(range.resolve(now).unwrap(), (Utc.ymd(2022, 5, 7).and_hms(21, 17, 0), now));
+        assert_eq!(
+            range.resolved(now).unwrap(),
+            TimeRange::absolute((Utc.ymd(2022, 5, 7).and_hms(21, 17, 0), now))
+        );
+    }
+
+    #[test]
+    fn out_of_range_times_are_errors() {
+        let now = Utc::now();
+        assert!(TimePoint::Relative(i64::MAX).resolve(now).is_err());
+        assert!(TimePoint::Relative(i64::MIN).resolve(now).is_err());
+        assert!(TimePoint::Relative(-1_000_000_000_000_000).resolve(now).is_err());
+        assert!(TimePoint::from_stream(&mut encoded(b'r', i64::MAX).as_slice()).is_err());
+        assert!(TimePoint::from_stream(&mut encoded(b'a', i64::MAX).as_slice()).is_err());
+        let offset = TimePoint::from_stream(&mut encoded(b'r', -MAX_DURATION_SECS).as_slice()).unwrap();
+        assert!(offset.resolve(now).is_ok());
+    }
+
+    #[test]
+    fn time_points_round_trip_on_the_wire() {
+        let time = Utc.ymd(2022, 5, 7).and_hms(21, 18, 0);
+        for point in [TimePoint::Absolute(time), TimePoint::Relative(-300)] {
+            let mut bytes = vec![];
+            point.write_to(&mut bytes).unwrap();
+            assert_eq!(TimePoint::from_stream(&mut bytes.as_slice()).unwrap(), point);
+        }
+    }
+}
@@ -0,0 +1,35 @@
+//! Fixtures shared by the tests of every module
+
+/// Path in the temporary folder for a test, unique to this process. Tests run in parallel, so
+/// each one must use its own `name`
+pub fn temp_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("tp1-test-{}-{}", std::process::id(), name));
+    path.to_string_lossy().into_owned()
+}
+
+/// Empty folder at `temp_path(name)`, removed when dropped
+pub struct TempDir(String);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = TempDir(temp_path(name));
+        std::fs::remove_dir_all(&dir.0).ok();
+        std::fs::create_dir_all(&dir.0).unwrap();
+        dir
+    }
+
+    pub fn path(&self) -> &str {
+        &self.0
+    }
+
+    /// Path of a file in the folder
+    pub fn join(&self, name: &str) -> String {
+        format!("{}/{}", self.0, name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}